@@ -0,0 +1,729 @@
+use region::Region;
+
+/*
+ * References:
+ *  - http://wiki.nesdev.com/w/index.php/APU
+ *  - http://wiki.nesdev.com/w/index.php/APU_Mixer
+ *
+ * Everything is clocked once per CPU cycle, the pulse timers only count
+ * on every other one.
+ */
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+pub const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+pub const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10,  9,  8,  7,  6,  5,  4,  3,  2,  1,  0,
+     0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15,
+];
+
+const NOISE_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+const NOISE_TABLE_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+const DMC_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+const DMC_TABLE_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/* CPU cycles at which the frame counter clocks the envelopes and length counters */
+const FRAME_STEPS: [u32; 4] = [7457, 14913, 22371, 29829];
+const FRAME_STEPS_5: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+
+const FRAME_STEPS_PAL: [u32; 4] = [8313, 16627, 24939, 33253];
+const FRAME_STEPS_5_PAL: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    looped: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.looped = val & 0x20 != 0;
+        self.constant = val & 0x10 != 0;
+        self.volume = val & 0x0f;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looped {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pulse {
+    /* Pulse 1 negates with ones' complement, pulse 2 with two's complement */
+    ones_complement: bool,
+    enabled: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: u8,
+    halt: bool,
+    envelope: Envelope,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement,
+            ..Pulse::default()
+        }
+    }
+
+    fn write8(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.duty = val >> 6;
+                self.halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            },
+            1 => {
+                self.sweep_enabled = val & 0x80 != 0;
+                self.sweep_period = (val >> 4) & 0x07;
+                self.sweep_negate = val & 0x08 != 0;
+                self.sweep_shift = val & 0x07;
+                self.sweep_reload = true;
+            },
+            2 => self.period = (self.period & 0x0700) | val as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((val as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.step = 0;
+                self.envelope.start = true;
+            },
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn target_period(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.period + change
+        } else if self.ones_complement {
+            self.period.saturating_sub(change + 1)
+        } else {
+            self.period.saturating_sub(change)
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.period < 8 || self.target_period() > 0x07ff
+    }
+
+    fn clock_quarter(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn clock_half(&mut self) {
+        if !self.halt && self.length > 0 {
+            self.length -= 1;
+        }
+
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.target_period();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.muted() || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Triangle {
+    enabled: bool,
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: u8,
+    control: bool,
+    linear_period: u8,
+    linear: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn write8(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.control = val & 0x80 != 0;
+                self.linear_period = val & 0x7f;
+            },
+            2 => self.period = (self.period & 0x0700) | val as u16,
+            3 => {
+                self.period = (self.period & 0x00ff) | ((val as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.linear_reload = true;
+            },
+            _ => {},
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length > 0 && self.linear > 0 {
+                self.step = (self.step + 1) & 31;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_quarter(&mut self) {
+        if self.linear_reload {
+            self.linear = self.linear_period;
+        } else if self.linear > 0 {
+            self.linear -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn clock_half(&mut self) {
+        if !self.control && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.step as usize]
+    }
+}
+
+struct Noise {
+    table: &'static [u16; 16],
+    enabled: bool,
+    mode: bool,
+    period: u16,
+    timer: u16,
+    shift: u16,
+    length: u8,
+    halt: bool,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new(table: &'static [u16; 16]) -> Noise {
+        Noise {
+            table,
+            enabled: false,
+            mode: false,
+            period: table[0],
+            timer: 0,
+            shift: 1,
+            length: 0,
+            halt: false,
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn write8(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            },
+            2 => {
+                self.mode = val & 0x80 != 0;
+                self.period = self.table[(val & 0x0f) as usize];
+            },
+            3 => {
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.envelope.start = true;
+            },
+            _ => {},
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+            self.shift = self.shift >> 1 | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_quarter(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn clock_half(&mut self) {
+        if !self.halt && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.shift & 0x01 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+struct Dmc {
+    table: &'static [u16; 16],
+    irq_enabled: bool,
+    irq: bool,
+    looped: bool,
+    period: u16,
+    timer: u16,
+    output: u8,
+
+    sample_addr: u16,
+    sample_length: u16,
+    addr: u16,
+    remaining: u16,
+    buffer: Option<u8>,
+
+    shift: u8,
+    bits: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn new(table: &'static [u16; 16]) -> Dmc {
+        Dmc {
+            table,
+            irq_enabled: false,
+            irq: false,
+            looped: false,
+            period: table[0],
+            timer: 0,
+            output: 0,
+
+            sample_addr: 0xc000,
+            sample_length: 1,
+            addr: 0xc000,
+            remaining: 0,
+            buffer: None,
+
+            shift: 0,
+            bits: 8,
+            silence: true,
+        }
+    }
+
+    fn write8(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = val & 0x80 != 0;
+                self.looped = val & 0x40 != 0;
+                self.period = self.table[(val & 0x0f) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            },
+            1 => self.output = val & 0x7f,
+            2 => self.sample_addr = 0xc000 | (val as u16) << 6,
+            _ => self.sample_length = (val as u16) << 4 | 1,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.remaining = 0;
+        } else if self.remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.addr = self.sample_addr;
+        self.remaining = self.sample_length;
+    }
+
+    /* The address the reader wants a byte from, if the buffer is empty */
+    fn fetch_addr(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.remaining > 0 {
+            Some(self.addr)
+        } else {
+            None
+        }
+    }
+
+    fn fill(&mut self, val: u8) {
+        self.buffer = Some(val);
+        self.addr = self.addr.wrapping_add(1) | 0x8000;
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            if self.looped {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+
+        if !self.silence {
+            if self.shift & 0x01 != 0 {
+                if self.output <= 125 {
+                    self.output += 2;
+                }
+            } else if self.output >= 2 {
+                self.output -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits -= 1;
+        if self.bits == 0 {
+            self.bits = 8;
+            match self.buffer.take() {
+                Some(val) => {
+                    self.silence = false;
+                    self.shift = val;
+                },
+                None => self.silence = true,
+            }
+        }
+    }
+}
+
+pub struct Apu {
+    pulses: [Pulse; 2],
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    frame_steps: &'static [u32; 4],
+    frame_steps_5: &'static [u32; 5],
+    cycle: u32,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+
+    cpu_frequency: f64,
+    sample_rate: u32,
+    sample_sum: f32,
+    sample_count: u32,
+    sample_clock: f64,
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu::with_sample_rate(SAMPLE_RATE)
+    }
+
+    pub fn with_sample_rate(sample_rate: u32) -> Apu {
+        Apu::with_region(Region::default(), sample_rate)
+    }
+
+    pub fn with_region(region: Region, sample_rate: u32) -> Apu {
+        let pal = region.pal_apu();
+        Apu {
+            pulses: [Pulse::new(true), Pulse::new(false)],
+            triangle: Triangle::default(),
+            noise: Noise::new(if pal { &NOISE_TABLE_PAL } else { &NOISE_TABLE }),
+            dmc: Dmc::new(if pal { &DMC_TABLE_PAL } else { &DMC_TABLE }),
+
+            frame_steps: if pal { &FRAME_STEPS_PAL } else { &FRAME_STEPS },
+            frame_steps_5: if pal { &FRAME_STEPS_5_PAL } else { &FRAME_STEPS_5 },
+            cycle: 0,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+
+            cpu_frequency: region.cpu_frequency(),
+            sample_rate,
+            sample_sum: 0.0,
+            sample_count: 0,
+            sample_clock: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    /* Reset silences every channel, like writing $00 to $4015 */
+    pub fn reset(&mut self) {
+        self.write8(0x4015, 0x00);
+        self.frame_irq = false;
+        self.cycle = 0;
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    /* $4015 without acknowledging the frame IRQ */
+    pub fn peek_status(&self) -> u8 {
+        (self.pulses[0].length > 0) as u8 |
+        ((self.pulses[1].length > 0) as u8) << 1 |
+        ((self.triangle.length > 0) as u8) << 2 |
+        ((self.noise.length > 0) as u8) << 3 |
+        ((self.dmc.remaining > 0) as u8) << 4 |
+        (self.frame_irq as u8) << 6 |
+        (self.dmc.irq as u8) << 7
+    }
+
+    pub fn write8(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4000 ..= 0x4003 => self.pulses[0].write8(addr & 3, val),
+            0x4004 ..= 0x4007 => self.pulses[1].write8(addr & 3, val),
+            0x4008 ..= 0x400b => self.triangle.write8(addr & 3, val),
+            0x400c ..= 0x400f => self.noise.write8(addr & 3, val),
+            0x4010 ..= 0x4013 => self.dmc.write8(addr & 3, val),
+            0x4015 => {
+                self.pulses[0].set_enabled(val & 0x01 != 0);
+                self.pulses[1].set_enabled(val & 0x02 != 0);
+                self.triangle.set_enabled(val & 0x04 != 0);
+                self.noise.set_enabled(val & 0x08 != 0);
+                self.dmc.set_enabled(val & 0x10 != 0);
+            },
+            0x4017 => {
+                self.five_step = val & 0x80 != 0;
+                self.irq_inhibit = val & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+
+                self.cycle = 0;
+                if self.five_step {
+                    self.clock_quarter();
+                    self.clock_half();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /* Runs one CPU cycle, `expansion` is the cartridge's audio output */
+    pub fn clock(&mut self, expansion: f32) {
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle & 1 == 1 {
+            for pulse in &mut self.pulses {
+                pulse.clock_timer();
+            }
+        }
+
+        self.cycle += 1;
+        self.clock_frame_counter();
+
+        self.sample_sum += self.output() + expansion;
+        self.sample_count += 1;
+        self.sample_clock += self.sample_rate as f64;
+        if self.sample_clock >= self.cpu_frequency {
+            self.sample_clock -= self.cpu_frequency;
+            /* Nothing has to clear them, so only the last second or so is kept */
+            let max = self.sample_rate as usize;
+            if self.samples.len() >= max {
+                self.samples.drain(..max / 2);
+            }
+            self.samples.push(self.sample_sum / self.sample_count as f32);
+            self.sample_sum = 0.0;
+            self.sample_count = 0;
+        }
+    }
+
+    fn clock_frame_counter(&mut self) {
+        let steps: &[u32] = if self.five_step { self.frame_steps_5 } else { self.frame_steps };
+        let step = match steps.iter().position(|&cycle| cycle == self.cycle) {
+            Some(step) => step,
+            None => return,
+        };
+
+        let last = step == steps.len() - 1;
+
+        /* The 4th step of the 5 step sequence does nothing */
+        if !(self.five_step && step == 3) {
+            self.clock_quarter();
+            if step == 1 || last {
+                self.clock_half();
+            }
+        }
+
+        if last {
+            self.cycle = 0;
+            if !self.five_step && !self.irq_inhibit {
+                self.frame_irq = true;
+            }
+        }
+    }
+
+    fn clock_quarter(&mut self) {
+        for pulse in &mut self.pulses {
+            pulse.clock_quarter();
+        }
+        self.triangle.clock_quarter();
+        self.noise.clock_quarter();
+    }
+
+    fn clock_half(&mut self) {
+        for pulse in &mut self.pulses {
+            pulse.clock_half();
+        }
+        self.triangle.clock_half();
+        self.noise.clock_half();
+    }
+
+    fn output(&self) -> f32 {
+        let pulses = (self.pulses[0].output() + self.pulses[1].output()) as f32;
+        let pulse_out = if pulses == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulses + 100.0)
+        };
+
+        let triangle = self.triangle.output() as f32;
+        let noise = self.noise.output() as f32;
+        let dmc = self.dmc.output as f32;
+        let tnd = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+
+        pulse_out + tnd_out
+    }
+
+    /* DMC sample fetches are done by the bus on the APU's behalf */
+    pub fn dmc_fetch_addr(&self) -> Option<u16> {
+        self.dmc.fetch_addr()
+    }
+
+    pub fn dmc_fill(&mut self, val: u8) {
+        self.dmc.fill(val);
+    }
+
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /* Samples since the last clear_samples(), at most about a second's worth */
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
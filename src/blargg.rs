@@ -0,0 +1,245 @@
+use cartridge;
+use mem::Mem;
+use nes::Nes;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+ * Runs test ROMs that report through PRG RAM, which is most of blargg's
+ * and many newer community tests:
+ *  - $6001-$6003 hold DE B0 61 once $6000 is valid
+ *  - $6000 is $80 while running, $81 when it wants the reset button
+ *    pressed (at least 100ms later), then the result, 0 for a pass
+ *  - $6004 onwards is a NUL terminated message
+ *
+ * References:
+ *  - http://blargg.8bitalley.com/nes-tests/
+ *  - http://wiki.nesdev.com/w/index.php/Emulator_tests
+ */
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const TEXT: u16 = 0x6004;
+
+const SIGNATURE_BYTES: [u8; 3] = [0xde, 0xb0, 0x61];
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+
+/* Frames to hold off pressing reset for, a little over 100ms */
+const RESET_DELAY: usize = 7;
+
+/* The message can't be longer than the rest of PRG RAM */
+const TEXT_MAX: u16 = 0x1ffc;
+
+#[derive(Debug)]
+pub enum Error {
+    Load(cartridge::Error),
+    Io(io::Error),
+    /* Still running, or never wrote the signature, after this many frames */
+    Timeout(usize),
+}
+
+impl From<cartridge::Error> for Error {
+    fn from(err: cartridge::Error) -> Self {
+        Error::Load(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestResult {
+    pub status: u8,
+    pub text: String,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.status == 0
+    }
+}
+
+/* What PRG RAM says about the test */
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Status {
+    /* No signature yet, so $6000 doesn't mean anything */
+    Unsigned,
+    Running,
+    Reset,
+    Done(TestResult),
+}
+
+/* Runs the ROM in `nes` until it reports a result, for at most `frames` frames */
+pub fn run(nes: &mut Nes, frames: usize) -> Result<TestResult, Error> {
+    let mut reset_at = None;
+
+    for frame in 0..frames {
+        nes.run_frame();
+
+        match status(nes.cpu().mem()) {
+            Status::Unsigned | Status::Running => {},
+            Status::Reset => match reset_at {
+                None => reset_at = Some(frame + RESET_DELAY),
+                Some(reset) if frame >= reset => {
+                    nes.soft_reset();
+                    reset_at = None;
+                },
+                Some(_) => {},
+            },
+            Status::Done(result) => return Ok(result),
+        }
+    }
+
+    Err(Error::Timeout(frames))
+}
+
+pub fn run_rom<P: AsRef<Path>>(path: P, frames: usize) -> Result<TestResult, Error> {
+    let mut nes = Nes::load_rom(path)?;
+    run(&mut nes, frames)
+}
+
+/* Runs every .nes file in `dir`, in name order */
+pub fn run_dir<P: AsRef<Path>>(dir: P, frames: usize) -> io::Result<Vec<(PathBuf, Result<TestResult, Error>)>> {
+    let mut roms = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_rom = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("nes"));
+        if is_rom {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+
+    Ok(roms.into_iter().map(|rom| {
+        let result = run_rom(&rom, frames);
+        (rom, result)
+    }).collect())
+}
+
+fn status<M: Mem>(mem: &M) -> Status {
+    let signed = (0..3).all(|i| mem.peek8(SIGNATURE + i) == SIGNATURE_BYTES[i as usize]);
+    if !signed {
+        return Status::Unsigned;
+    }
+
+    match mem.peek8(STATUS) {
+        STATUS_RUNNING => Status::Running,
+        STATUS_RESET => Status::Reset,
+        status => Status::Done(TestResult {
+            status,
+            text: read_text(mem),
+        }),
+    }
+}
+
+fn read_text<M: Mem>(mem: &M) -> String {
+    let bytes: Vec<u8> = (0..TEXT_MAX)
+        .map(|i| mem.peek8(TEXT + i))
+        .take_while(|&byte| byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cartridge::Cartridge;
+    use mem::FlatMem;
+
+    /* PRG RAM as a test leaves it, with the signature written */
+    fn prg_ram(status: u8, text: &[u8]) -> FlatMem {
+        let mut mem = FlatMem::new();
+        mem.load(STATUS, &[status]);
+        mem.load(SIGNATURE, &SIGNATURE_BYTES);
+        mem.load(TEXT, text);
+        mem
+    }
+
+    #[test]
+    fn signature() {
+        let mut mem = FlatMem::new();
+        assert_eq!(status(&mem), Status::Unsigned);
+        mem.load(SIGNATURE, &[0xde, 0xb0, 0x60]);
+        assert_eq!(status(&mem), Status::Unsigned);
+        mem.load(SIGNATURE, &SIGNATURE_BYTES);
+        assert!(status(&mem) != Status::Unsigned);
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(status(&prg_ram(0x80, b"")), Status::Running);
+        assert_eq!(status(&prg_ram(0x81, b"")), Status::Reset);
+
+        let passed = TestResult { status: 0x00, text: String::from("Passed") };
+        assert_eq!(status(&prg_ram(0x00, b"Passed\n\0")), Status::Done(passed));
+
+        match status(&prg_ram(0x03, b"Failed #3\n\0")) {
+            Status::Done(ref result) if !result.passed() => assert_eq!(result.text, "Failed #3"),
+            other => panic!("expected a failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(read_text(&prg_ram(0x00, b"line 1\nline 2\n\n\0junk")), "line 1\nline 2");
+        assert_eq!(read_text(&prg_ram(0x00, b"\xffok\0")), "\u{fffd}ok");
+
+        /* Without a NUL it stops at the end of PRG RAM */
+        let mut mem = prg_ram(0x00, &[b'a'; TEXT_MAX as usize]);
+        mem.load(0x8000, b"b");
+        assert_eq!(read_text(&mem), "a".repeat(TEXT_MAX as usize));
+    }
+
+    /*
+     * An NROM test that asks for a reset, then passes once it comes back
+     * up and sees $81 still in $6000.
+     */
+    fn reset_test() -> Nes {
+        let program: &[u8] = &[
+            0xad, 0x00, 0x60,       /* $8000: LDA $6000 */
+            0xc9, 0x81,             /* $8003: CMP #$81 */
+            0xf0, 0x17,             /* $8005: BEQ $801e */
+            0xa9, 0x81,             /* $8007: LDA #$81 */
+            0x8d, 0x00, 0x60,       /* $8009: STA $6000 */
+            0xa9, 0xde, 0x8d, 0x01, 0x60,
+            0xa9, 0xb0, 0x8d, 0x02, 0x60,
+            0xa9, 0x61, 0x8d, 0x03, 0x60,
+            0x4c, 0x1b, 0x80,       /* $801b: JMP $801b */
+            0xa9, 0x6f, 0x8d, 0x04, 0x60,
+            0xa9, 0x6b, 0x8d, 0x05, 0x60,
+            0xa9, 0x00, 0x8d, 0x06, 0x60,
+            0x8d, 0x00, 0x60,       /* $802d: STA $6000 */
+            0x4c, 0x30, 0x80,       /* $8030: JMP $8030 */
+        ];
+
+        let mut prg_rom = vec![0xea; 0x4000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x3ffa..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+
+        let mut file = b"NES\x1a\x01\x01".to_vec();
+        file.extend_from_slice(&[0; 10]);
+        file.extend(prg_rom);
+        file.extend(vec![0; 0x2000]);
+        Nes::new(Cartridge::new(&mut file.as_slice()).unwrap())
+    }
+
+    #[test]
+    fn reset_delay() {
+        /* $81 shows up on the first frame, and reset is held off for RESET_DELAY more */
+        match run(&mut reset_test(), RESET_DELAY + 1) {
+            Err(Error::Timeout(_)) => {},
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+
+        let result = run(&mut reset_test(), RESET_DELAY + 2).unwrap();
+        assert!(result.passed());
+        assert_eq!(result.text, "ok");
+    }
+}
@@ -1,75 +1,316 @@
 #![allow(dead_code)]
 
+use crc::crc32;
 use mem::Mem;
+use mapper::Registry;
+use ram::RamInit;
+use region::Region;
 use std::io::{self, Read};
 
+/* Bigger than any real board, the plain NES 2.0 size field tops out just under this */
+pub const MAX_ROM_SIZE: u64 = 64 << 20;
+
 #[derive(Debug)]
 pub enum Error {
-    IoError(io::Error),
+    Io(io::Error),
     BadFileFormat,
-    UnsupportedMapper(u8),
+    /* A PRG or CHR ROM size bigger than MAX_ROM_SIZE */
+    RomTooLarge(u64),
+    /* The header gives no PRG ROM, which every board needs */
+    NoPrgRom,
+    UnsupportedMapper(u16, u8),
 }
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
-        Error::IoError(err)
+        Error::Io(err)
     }
 }
 
-pub type Cartridge = Mem;
-impl Cartridge {
-    pub fn new(stream: &mut Read) -> Result<Box<Cartridge>, Error> {
-        let mut header = [0; 16];
-        try!(stream.read(&mut header));
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+}
+
+impl Mirroring {
+    /* Offset into the console's 2K of nametable RAM for a $2000-$2FFF address */
+    pub fn vram_offset(self, addr: u16) -> usize {
+        let addr = addr as usize & 0x0fff;
+        let page = match self {
+            Mirroring::Horizontal => addr >> 11,
+            Mirroring::Vertical => (addr >> 10) & 1,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+        };
+        page << 10 | (addr & 0x03ff)
+    }
+}
+
+/*
+ * A cartridge sits on both buses: Mem covers the CPU side ($4020-$FFFF),
+ * the rest of the trait covers the PPU side ($0000-$3EFF) and the
+ * signals the board drives back into the console.
+ */
+pub trait Mapper: Mem {
+    fn chr_read8(&self, addr: u16) -> u8;
+    fn chr_write8(&mut self, addr: u16, val: u8);
+    fn mirroring(&self) -> Mirroring;
+
+    fn nametable_read8(&self, vram: &[u8], addr: u16) -> u8 {
+        vram[self.mirroring().vram_offset(addr)]
+    }
+
+    fn nametable_write8(&mut self, vram: &mut [u8], addr: u16, val: u8) {
+        vram[self.mirroring().vram_offset(addr)] = val;
+    }
+
+    /* Whether the board answers a CPU read, everything else sees open bus */
+    fn drives_cpu_bus(&self, addr: u16) -> bool {
+        addr >= 0x8000
+    }
+
+    /* Back to the board's power on state, except for RAM */
+    fn power_on(&mut self) {}
+
+    /* The console's reset button, which most boards never see */
+    fn reset(&mut self) {}
+
+    /* Fills PRG RAM at power on */
+    fn init_ram(&mut self, _init: RamInit) {}
+
+    /* Called when the board is plugged in, for boards with their own timing */
+    fn set_region(&mut self, _region: Region) {}
 
+    /* CPU writes to $2000-$401F, for boards that watch the PPU/APU registers */
+    fn snoop_write8(&mut self, _addr: u16, _val: u8) {}
+
+    /* Called once per CPU cycle */
+    fn clock(&mut self) {}
+
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /* Expansion audio, mixed in by the APU */
+    fn audio(&self) -> f32 {
+        0.0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub nes2: bool,
+    /* Only NES 2.0 headers have a reliable region */
+    pub region: Option<Region>,
+}
+
+impl Header {
+    pub fn new(header: &[u8; 16]) -> Result<Header, Error> {
         let magic = &header[0..4];
         if magic != b"NES\x1a" {
             return Err(Error::BadFileFormat);
         }
 
-        let trainer = (header[6] & 0x04) != 0;
+        let nes2 = header[7] & 0x0c == 0x08;
+        let mut mapper = (header[7] & 0xf0 | header[6] >> 4) as u16;
+        let mut submapper = 0;
+        let mut region = None;
+        if nes2 {
+            region = Region::from_nes2(header[12]);
+            mapper |= (header[8] as u16 & 0x0f) << 8;
+            submapper = header[8] >> 4;
+        }
+
+        let mirroring = if header[6] & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        Ok(Header {
+            mapper,
+            submapper,
+            mirroring,
+            battery: header[6] & 0x02 != 0,
+            nes2,
+            region,
+        })
+    }
+}
+
+/* The contents of an iNES file, before a mapper has been picked for it */
+pub struct Rom {
+    pub header: Header,
+    pub prg_rom: Box<[u8]>,
+    pub chr_rom: Box<[u8]>,
+}
+
+impl Rom {
+    pub fn new(stream: &mut dyn Read) -> Result<Rom, Error> {
+        let mut raw_header = [0; 16];
+        stream.read_exact(&mut raw_header)?;
+        let header = Header::new(&raw_header)?;
+
+        let trainer = (raw_header[6] & 0x04) != 0;
         if trainer {
-            try!(stream.read(&mut [0; 512]));
+            stream.read_exact(&mut [0; 512])?;
         }
 
-        let prg_rom_size = (header[4] as usize) << 14;
-        let mut prg_rom = vec![0; prg_rom_size];
-        try!(stream.read(&mut prg_rom));
-        let prg_rom = prg_rom.into_boxed_slice();
-
-        let chr_rom_size = (header[5] as usize) << 13;
-        let mut chr_rom = vec![0; chr_rom_size];
-        try!(stream.read(&mut chr_rom));
-        let chr_rom = chr_rom.into_boxed_slice();
-
-        let mapper = (header[7] & 0xf0) | (header[6] >> 4);
-        match mapper {
-            0 => Ok(Box::new(Mapper0 {
-                prg_rom: prg_rom,
-                chr_rom: chr_rom,
-            })),
-            _ => Err(Error::UnsupportedMapper(mapper)),
+        let (prg_rom_size, chr_rom_size) = if header.nes2 {
+            (nes2_rom_size(raw_header[4], raw_header[9] & 0x0f, 0x4000),
+             nes2_rom_size(raw_header[5], raw_header[9] >> 4, 0x2000))
+        } else {
+            (raw_header[4] as u64 * 0x4000, raw_header[5] as u64 * 0x2000)
+        };
+        if prg_rom_size == 0 {
+            return Err(Error::NoPrgRom);
         }
+
+        let prg_rom = read_rom(stream, prg_rom_size)?;
+        let chr_rom = read_rom(stream, chr_rom_size)?;
+
+        Ok(Rom {
+            header,
+            prg_rom,
+            chr_rom,
+        })
+    }
+
+    /* CRC32 of the PRG and CHR ROM, without the header, which is how game databases identify ROMs */
+    pub fn crc32(&self) -> u32 {
+        let mut data = self.prg_rom.to_vec();
+        data.extend_from_slice(&self.chr_rom);
+        crc32(&data)
     }
 }
 
-pub struct Mapper0 {
-    prg_rom: Box<[u8]>,
-    chr_rom: Box<[u8]>,
+/*
+ * NES 2.0 sizes are 12 bits, in units of `unit`. An MSB nibble of $F
+ * means the LSB is an exponent and multiplier instead: 2^E * (MM * 2 + 1)
+ * bytes, where E is bits 2-7 and MM bits 0-1.
+ */
+fn nes2_rom_size(lsb: u8, msb: u8, unit: u64) -> u64 {
+    if msb == 0x0f {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as u64 * 2 + 1;
+        (1u64 << exponent).saturating_mul(multiplier)
+    } else {
+        ((msb as u64) << 8 | lsb as u64) * unit
+    }
+}
+
+/* Reads as it goes, so a lying header can't make it allocate more than the file holds */
+fn read_rom(stream: &mut dyn Read, size: u64) -> Result<Box<[u8]>, Error> {
+    if size > MAX_ROM_SIZE {
+        return Err(Error::RomTooLarge(size));
+    }
+
+    let mut rom = Vec::new();
+    stream.take(size).read_to_end(&mut rom)?;
+    if (rom.len() as u64) < size {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "ROM data is truncated")));
+    }
+    Ok(rom.into_boxed_slice())
+}
+
+pub type Cartridge = dyn Mapper;
+impl Cartridge {
+    pub fn new(stream: &mut dyn Read) -> Result<Box<Cartridge>, Error> {
+        Cartridge::with_registry(stream, &Registry::default())
+    }
+
+    pub fn with_registry(stream: &mut dyn Read, registry: &Registry) -> Result<Box<Cartridge>, Error> {
+        let rom = Rom::new(stream)?;
+        registry.build(rom)
+    }
 }
 
-impl Mem for Mapper0 {
-    fn read8(&self, addr: u16) -> u8 {
-        match addr {
-            0x8000 ... 0xFFFF =>
-                self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()],
-            _ => panic!("not yet implemented Cartridge::read8(0x{:04X})", addr),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::nrom::Nrom;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn nes2_header(prg_lsb: u8, chr_lsb: u8, msb: u8) -> Vec<u8> {
+        let mut header = b"NES\x1a".to_vec();
+        header.extend_from_slice(&[prg_lsb, chr_lsb, 0x00, 0x08, 0x00, msb, 0, 0, 0, 0, 0, 0]);
+        header
+    }
+
+    #[test]
+    fn nes2_sizes() {
+        assert_eq!(nes2_rom_size(0x02, 0x00, 0x4000), 0x8000);
+        assert_eq!(nes2_rom_size(0x00, 0x01, 0x4000), 0x400000);
+        /* 2^14 * 1 and 2^10 * 3 */
+        assert_eq!(nes2_rom_size(14 << 2, 0x0f, 0x4000), 0x4000);
+        assert_eq!(nes2_rom_size(10 << 2 | 1, 0x0f, 0x2000), 0x0c00);
+        assert_eq!(nes2_rom_size(0xff, 0x0f, 0x4000), u64::MAX);
+    }
+
+    #[test]
+    fn exponent_size() {
+        /* 16K of PRG ROM and 3K of CHR ROM, written as exponents */
+        let mut file = nes2_header(14 << 2, 10 << 2 | 1, 0xff);
+        file.extend(vec![0xea; 0x4000 + 0x0c00]);
+
+        let rom = Rom::new(&mut file.as_slice()).unwrap();
+        assert_eq!(rom.prg_rom.len(), 0x4000);
+        assert_eq!(rom.chr_rom.len(), 0x0c00);
+    }
+
+    #[test]
+    fn huge_size() {
+        let file = nes2_header(63 << 2 | 3, 0, 0x0f);
+        match Rom::new(&mut file.as_slice()) {
+            Err(Error::RomTooLarge(_)) => {},
+            other => panic!("expected RomTooLarge, got {:?}", other.map(|rom| rom.prg_rom.len())),
+        }
+    }
+
+    #[test]
+    fn no_prg_rom() {
+        let mut file = nes2_header(0x00, 0x01, 0x00);
+        file.extend(vec![0; 0x2000]);
+        match Rom::new(&mut file.as_slice()) {
+            Err(Error::NoPrgRom) => {},
+            other => panic!("expected NoPrgRom, got {:?}", other.map(|rom| rom.prg_rom.len())),
         }
     }
 
-    fn write8(&mut self, addr: u16, val: u8) {
-        match addr {
-            _ => panic!("not yet implemented Cartridge::write8(0x{:04X},0x{:02X})", addr, val),
+    #[test]
+    fn truncated() {
+        let mut file = nes2_header(0x02, 0, 0x00);
+        file.extend(vec![0; 0x100]);
+        match Rom::new(&mut file.as_slice()) {
+            Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {},
+            other => panic!("expected UnexpectedEof, got {:?}", other.map(|rom| rom.prg_rom.len())),
         }
     }
+
+    #[test]
+    fn closure_constructor() {
+        let built = Rc::new(Cell::new(0));
+        let mut registry = Registry::new();
+        {
+            let built = built.clone();
+            registry.register(0, None, "NROM", move |rom| {
+                built.set(built.get() + 1);
+                Ok(Box::new(Nrom::new(rom)))
+            });
+        }
+
+        let mut file = nes2_header(0x01, 0x01, 0x00);
+        file.extend(vec![0; 0x4000 + 0x2000]);
+        assert!(Cartridge::with_registry(&mut file.as_slice(), &registry).is_ok());
+        assert_eq!(built.get(), 1);
+    }
 }
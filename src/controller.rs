@@ -0,0 +1,63 @@
+/*
+ * References:
+ *  - http://wiki.nesdev.com/w/index.php/Standard_controller
+ */
+
+pub const BUTTON_A:      u8 = 1 << 0;
+pub const BUTTON_B:      u8 = 1 << 1;
+pub const BUTTON_SELECT: u8 = 1 << 2;
+pub const BUTTON_START:  u8 = 1 << 3;
+pub const BUTTON_UP:     u8 = 1 << 4;
+pub const BUTTON_DOWN:   u8 = 1 << 5;
+pub const BUTTON_LEFT:   u8 = 1 << 6;
+pub const BUTTON_RIGHT:  u8 = 1 << 7;
+
+#[derive(Debug, Default)]
+pub struct Controller {
+    buttons: u8,
+    strobe: bool,
+    shift: u8,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller::default()
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift = buttons;
+        }
+    }
+
+    pub fn write_strobe(&mut self, val: u8) {
+        self.strobe = val & 0x01 != 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    /* Buttons come out A first, then 1s once all eight have been read */
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 0x01;
+        }
+
+        let shift = self.shift;
+        self.shift = shift >> 1 | 0x80;
+        shift & 0x01
+    }
+
+    /* The next bit read would return, without shifting */
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            return self.buttons & 0x01;
+        }
+        self.shift & 0x01
+    }
+}
@@ -1,9 +1,14 @@
+use debugger::{self, Access, Condition, Debugger, Point, Register};
+use disasm::{self, Registers, Symbols};
 use mem::Mem;
 use opcode::{AddressingMode, Instruction};
+use ppu;
 
-use std::io::{self, BufReader};
+use std::io;
 use std::io::prelude::*;
-use std::fs::File;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::marker::PhantomData;
 
 /*
  * References:
@@ -21,58 +26,199 @@ const DECIMAL_FLAG:  u8 = 1 << 3;
 const OVERFLOW_FLAG: u8 = 1 << 6;
 const NEGATIVE_FLAG: u8 = 1 << 7;
 
+const NMI_VECTOR: u16 = 0xfffa;
+const RESET_VECTOR: u16 = 0xfffc;
+const IRQ_VECTOR: u16 = 0xfffe;
+
+/* How far `continue` runs without hitting anything, a few seconds of emulated time */
+const CONTINUE_LIMIT: usize = 10_000_000;
+
+/*
+ * What sets 6502s apart, so the same core can run other machines. The
+ * 2A03 is an NMOS 6502 with decimal mode disconnected: SED and CLD still
+ * change the D flag, but ADC and SBC ignore it.
+ */
+pub trait Variant {
+    const DECIMAL_MODE: bool;
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ricoh2A03;
+
+impl Variant for Ricoh2A03 {
+    const DECIMAL_MODE: bool = false;
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Nmos6502;
+
+impl Variant for Nmos6502 {
+    const DECIMAL_MODE: bool = true;
+}
+
+#[derive(Debug)]
+pub struct LogMismatch {
+    pub line: usize,
+    pub expected: String,
+    pub obtained: String,
+}
+
 #[derive(Debug)]
-pub struct Cpu<M: Mem> {
+pub struct Cpu<M: Mem, V: Variant = Ricoh2A03> {
     clock: usize,
     mem: M,
     pc: u16,
     sp: u8,
     a: u8, x: u8, y: u8,
     status: u8,
+
+    /*
+     * Interrupts are polled at the end of every cycle, but the CPU acts on
+     * what it saw at the end of the second to last cycle of an instruction,
+     * so the *_latched values lag one cycle behind.
+     */
+    nmi_pending: bool,
+    nmi_latched: bool,
+    irq_pending: bool,
+    irq_latched: bool,
+
+    debugger: Debugger,
+    variant: PhantomData<V>,
 }
 
 impl<M: Mem> Cpu<M> {
     pub fn new(mem: M) -> Cpu<M> {
+        Cpu::with_variant(mem, Ricoh2A03)
+    }
+}
+
+impl<M: Mem, V: Variant> Cpu<M, V> {
+    pub fn with_variant(mem: M, _variant: V) -> Cpu<M, V> {
         Cpu {
-            mem: mem,
+            mem,
             clock: 0,
             pc: 0xc000,
             sp: 0xfd,
             a: 0, x: 0, y: 0,
             status: 0x34,
+            nmi_pending: false,
+            nmi_latched: false,
+            irq_pending: false,
+            irq_latched: false,
+            debugger: Debugger::new(),
+            variant: PhantomData,
+        }
+    }
+
+    pub fn clock(&self) -> usize {
+        self.clock
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn mem(&self) -> &M {
+        &self.mem
+    }
+
+    pub fn mem_mut(&mut self) -> &mut M {
+        &mut self.mem
+    }
+
+    pub fn register(&self, register: Register) -> u16 {
+        match register {
+            Register::A => self.a as u16,
+            Register::X => self.x as u16,
+            Register::Y => self.y as u16,
+            Register::P => self.get_status() as u16,
+            Register::SP => self.sp as u16,
+            Register::PC => self.pc,
+        }
+    }
+
+    pub fn set_register(&mut self, register: Register, val: u16) {
+        match register {
+            Register::A => self.a = val as u8,
+            Register::X => self.x = val as u8,
+            Register::Y => self.y = val as u8,
+            Register::P => self.set_status(val as u8),
+            Register::SP => self.sp = val as u8,
+            Register::PC => self.pc = val,
         }
     }
 
-    pub fn reset(&mut self) {
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    /* The registers start cleared, SP ends up at $FD after the reset sequence */
+    pub fn power_on(&mut self) {
         self.clock = 0;
-        self.pc = 0xc000;
-        self.sp = 0xfd;
+        self.sp = 0x00;
         self.a = 0;
         self.x = 0;
         self.y = 0;
         self.status = 0x34;
+        self.reset_sequence();
+    }
+
+    /* Registers are kept, apart from SP and the I flag, and the rest of the system is reset too */
+    pub fn soft_reset(&mut self) {
+        self.mem.soft_reset();
+        self.reset_sequence();
     }
 
+    /* Runs one instruction, or one interrupt sequence if an interrupt is pending */
     pub fn step(&mut self) {
+        if self.nmi_latched {
+            self.nmi_pending = false;
+            self.nmi_latched = false;
+            self.interrupt(NMI_VECTOR);
+            return;
+        }
+
+        if self.irq_latched {
+            self.interrupt(IRQ_VECTOR);
+            return;
+        }
+
         let opcode = self.next8();
+        let instruction = Instruction::from(opcode);
+        let writes = instruction.writes();
 
         let addr = match AddressingMode::from(opcode) {
-            AddressingMode::Implied => None,
-            AddressingMode::Accumulator => None,
+            /* One byte instructions still read the byte after the opcode */
+            AddressingMode::Implied | AddressingMode::Accumulator => {
+                let pc = self.pc;
+                self.read8(pc);
+                None
+            },
             AddressingMode::Immediate => Some(self.immediate()),
             AddressingMode::ZeroPage => Some(self.zero_page()),
             AddressingMode::ZeroPageX => Some(self.zero_page_x()),
             AddressingMode::ZeroPageY => Some(self.zero_page_y()),
             AddressingMode::Relative => Some(self.relative()),
-            AddressingMode::Absolute => Some(self.absolute()),
-            AddressingMode::AbsoluteX => Some(self.absolute_x()),
-            AddressingMode::AbsoluteY => Some(self.absolute_y()),
+            AddressingMode::Absolute => match instruction {
+                Instruction::JSR => None,
+                _ => Some(self.absolute()),
+            },
+            AddressingMode::AbsoluteX => Some(self.absolute_x(writes)),
+            AddressingMode::AbsoluteY => Some(self.absolute_y(writes)),
             AddressingMode::Indirect => Some(self.indirect()),
             AddressingMode::IndexedIndirect => Some(self.indexed_indirect()),
-            AddressingMode::IndirectIndexed => Some(self.indirect_indexed()),
+            AddressingMode::IndirectIndexed => Some(self.indirect_indexed(writes)),
         };
 
-        match Instruction::from(opcode) {
+        match instruction {
             Instruction::LDA => self.lda(addr.unwrap()),
             Instruction::LDX => self.ldx(addr.unwrap()),
             Instruction::LDY => self.ldy(addr.unwrap()),
@@ -109,7 +255,7 @@ impl<M: Mem> Cpu<M> {
             Instruction::ROL => self.rol(addr),
             Instruction::ROR => self.ror(addr),
             Instruction::JMP => self.jmp(addr.unwrap()),
-            Instruction::JSR => self.jsr(addr.unwrap()),
+            Instruction::JSR => self.jsr(),
             Instruction::RTS => self.rts(),
             Instruction::BCC => self.bcc(addr.unwrap()),
             Instruction::BCS => self.bcs(addr.unwrap()),
@@ -127,19 +273,33 @@ impl<M: Mem> Cpu<M> {
             Instruction::SED => self.sed(),
             Instruction::SEI => self.sei(),
             Instruction::BRK => self.brk(),
-            Instruction::NOP => self.nop(),
+            Instruction::NOP => self.nop(addr),
             Instruction::RTI => self.rti(),
 
-            /* Treat unofficial instructions as NOP */
-            _ => self.nop(),
+            Instruction::LAX => self.lax(addr.unwrap()),
+            Instruction::SAX => self.sax(addr.unwrap()),
+            Instruction::DCP => self.dcp(addr.unwrap()),
+            Instruction::ISC => self.isc(addr.unwrap()),
+            Instruction::SLO => self.slo(addr.unwrap()),
+            Instruction::RLA => self.rla(addr.unwrap()),
+            Instruction::SRE => self.sre(addr.unwrap()),
+            Instruction::RRA => self.rra(addr.unwrap()),
+            Instruction::ANC => self.anc(addr.unwrap()),
+            Instruction::ALR => self.alr(addr.unwrap()),
+            Instruction::ARR => self.arr(addr.unwrap()),
+            Instruction::AXS => self.axs(addr.unwrap()),
+            Instruction::LAS => self.las(addr.unwrap()),
+
+            /* The unstable ones and STP are treated as NOP */
+            _ => self.nop(addr),
         };
     }
 
     /* Addressing Modes */
     fn immediate(&mut self) -> u16 {
-        let val = self.pc;
-        self.next8();
-        val
+        let addr = self.pc;
+        self.pc = self.pc.wrapping_add(1);
+        addr
     }
 
     fn zero_page(&mut self) -> u16 {
@@ -147,27 +307,36 @@ impl<M: Mem> Cpu<M> {
     }
 
     fn zero_page_x(&mut self) -> u16 {
-        (self.next8().wrapping_add(self.x)) as u16
+        let base = self.next8();
+        self.read8(base as u16);
+        base.wrapping_add(self.x) as u16
     }
 
     fn zero_page_y(&mut self) -> u16 {
-        (self.next8().wrapping_add(self.y)) as u16
+        let base = self.next8();
+        self.read8(base as u16);
+        base.wrapping_add(self.y) as u16
     }
 
     fn relative(&mut self) -> u16 {
-        (self.next8() as u16).wrapping_add(self.pc)
+        let offset = self.next8() as i8;
+        self.pc.wrapping_add(offset as u16)
     }
 
     fn absolute(&mut self) -> u16 {
         self.next16()
     }
 
-    fn absolute_x(&mut self) -> u16 {
-        self.next16().wrapping_add(self.x as u16)
+    fn absolute_x(&mut self, writes: bool) -> u16 {
+        let base = self.next16();
+        let x = self.x;
+        self.indexed(base, x, writes)
     }
 
-    fn absolute_y(&mut self) -> u16 {
-        self.next16().wrapping_add(self.y as u16)
+    fn absolute_y(&mut self, writes: bool) -> u16 {
+        let base = self.next16();
+        let y = self.y;
+        self.indexed(base, y, writes)
     }
 
     fn indirect(&mut self) -> u16 {
@@ -177,12 +346,28 @@ impl<M: Mem> Cpu<M> {
 
     fn indexed_indirect(&mut self) -> u16 {
         let addr = self.next8();
+        self.read8(addr as u16);
         self.read16_zero_page(addr.wrapping_add(self.x) as u16)
     }
 
-    fn indirect_indexed(&mut self) -> u16 {
+    fn indirect_indexed(&mut self, writes: bool) -> u16 {
         let addr = self.next8();
-        self.read16_zero_page(addr as u16).wrapping_add(self.y as u16)
+        let base = self.read16_zero_page(addr as u16);
+        let y = self.y;
+        self.indexed(base, y, writes)
+    }
+
+    /*
+     * The high byte of an indexed address is fixed up a cycle late,
+     * the read from the half-computed address is always done by
+     * stores/read-modify-writes and by loads that cross a page
+     */
+    fn indexed(&mut self, base: u16, offset: u8, writes: bool) -> u16 {
+        let addr = base.wrapping_add(offset as u16);
+        if writes || (base ^ addr) & 0xff00 != 0 {
+            self.read8(base & 0xff00 | addr & 0x00ff);
+        }
+        addr
     }
 
     /* Load / Store */
@@ -269,12 +454,14 @@ impl<M: Mem> Cpu<M> {
     }
 
     fn pla(&mut self) {
+        self.stack_dummy_read();
         let val = self.pull8();
         self.a = val;
         self.set_zn(val);
     }
 
     fn plp(&mut self) {
+        self.stack_dummy_read();
         let status = self.pull8();
         self.set_status(status);
     }
@@ -310,8 +497,17 @@ impl<M: Mem> Cpu<M> {
 
     /* Arithmetic */
     fn adc(&mut self, addr: u16) {
+        let val = self.read8(addr);
+        self.adc_base(val);
+    }
+
+    fn adc_base(&mut self, val: u8) {
+        if V::DECIMAL_MODE && self.get_flag(DECIMAL_FLAG) {
+            return self.adc_decimal(val);
+        }
+
         let a = self.a as u16;
-        let b = self.read8(addr) as u16;
+        let b = val as u16;
         let c = self.get_flag(CARRY_FLAG) as u16;
         let result = a.wrapping_add(b).wrapping_add(c);
 
@@ -325,8 +521,17 @@ impl<M: Mem> Cpu<M> {
     }
 
     fn sbc(&mut self, addr: u16) {
+        let val = self.read8(addr);
+        self.sbc_base(val);
+    }
+
+    fn sbc_base(&mut self, val: u8) {
+        if V::DECIMAL_MODE && self.get_flag(DECIMAL_FLAG) {
+            return self.sbc_decimal(val);
+        }
+
         let a = self.a as u16;
-        let b = self.read8(addr) as u16;
+        let b = val as u16;
         let c = !self.get_flag(CARRY_FLAG) as u16;
         let result = a.wrapping_sub(b).wrapping_sub(c);
 
@@ -339,6 +544,61 @@ impl<M: Mem> Cpu<M> {
         self.set_zn(result as u8);
     }
 
+    /*
+     * Decimal mode on an NMOS 6502 adjusts each digit of the sum as it
+     * goes. Only C is valid: Z comes from the binary sum, and N and V from
+     * the sum before the high digit was adjusted.
+     *
+     * References:
+     *  - http://www.6502.org/tutorials/decimal_mode.html
+     */
+    fn adc_decimal(&mut self, val: u8) {
+        let a = self.a;
+        let c = self.get_flag(CARRY_FLAG) as u8;
+
+        let mut low = (a & 0x0f) as u16 + (val & 0x0f) as u16 + c as u16;
+        if low >= 0x0a {
+            low = ((low + 0x06) & 0x0f) + 0x10;
+        }
+        let mut result = (a & 0xf0) as u16 + (val & 0xf0) as u16 + low;
+
+        self.set_flag(ZERO_FLAG, a.wrapping_add(val).wrapping_add(c) == 0);
+        self.set_flag(NEGATIVE_FLAG, result & 0x80 != 0);
+        self.set_flag(OVERFLOW_FLAG,
+                        (a ^ val) & 0x80 == 0 &&
+                        (a as u16 ^ result) & 0x80 != 0);
+
+        if result >= 0xa0 {
+            result += 0x60;
+        }
+        self.set_flag(CARRY_FLAG, result >= 0x100);
+        self.a = result as u8;
+    }
+
+    /* SBC in decimal mode only adjusts A, all the flags are the binary ones */
+    fn sbc_decimal(&mut self, val: u8) {
+        let a = self.a;
+        let borrow = !self.get_flag(CARRY_FLAG) as u8;
+
+        let mut low = (a & 0x0f) as i16 - (val & 0x0f) as i16 - borrow as i16;
+        if low < 0 {
+            low = ((low - 0x06) & 0x0f) - 0x10;
+        }
+        let mut result = (a & 0xf0) as i16 - (val & 0xf0) as i16 + low;
+        if result < 0 {
+            result -= 0x60;
+        }
+
+        let binary = (a as u16).wrapping_sub(val as u16).wrapping_sub(borrow as u16);
+        self.set_flag(CARRY_FLAG, (binary & 0x0100) == 0);
+        self.set_flag(OVERFLOW_FLAG,
+                        (a ^ val) & 0x80 != 0 &&
+                        (a as u16 ^ binary) & 0x80 != 0);
+        self.set_zn(binary as u8);
+
+        self.a = result as u8;
+    }
+
     fn cmp_base(&mut self, a: u8, b: u8) {
         let result = a.wrapping_sub(b);
         self.set_flag(CARRY_FLAG, a >= b);
@@ -366,7 +626,9 @@ impl<M: Mem> Cpu<M> {
 
     /* Increments & Decrements */
     fn inc(&mut self, addr: u16) {
-        let result = self.read8(addr).wrapping_add(1);
+        let val = self.read8(addr);
+        self.write8(addr, val);
+        let result = val.wrapping_add(1);
         self.write8(addr, result);
         self.set_zn(result);
     }
@@ -384,7 +646,9 @@ impl<M: Mem> Cpu<M> {
     }
 
     fn dec(&mut self, addr: u16) {
-        let result = self.read8(addr).wrapping_sub(1);
+        let val = self.read8(addr);
+        self.write8(addr, val);
+        let result = val.wrapping_sub(1);
         self.write8(addr, result);
         self.set_zn(result);
     }
@@ -403,9 +667,9 @@ impl<M: Mem> Cpu<M> {
 
 
     /* Shifts */
-    fn shl_base(&mut self, addr: Option<u16>, c: bool) {
+    fn shl_base(&mut self, addr: Option<u16>, c: bool) -> u8 {
         let val = match addr {
-            Some(addr) => self.read8(addr),
+            Some(addr) => self.read_modify(addr),
             None => self.a,
         };
 
@@ -417,11 +681,12 @@ impl<M: Mem> Cpu<M> {
             Some(addr) => self.write8(addr, result),
             None => self.a = result,
         };
+        result
     }
 
-    fn shr_base(&mut self, addr: Option<u16>, c: bool) {
+    fn shr_base(&mut self, addr: Option<u16>, c: bool) -> u8 {
         let val = match addr {
-            Some(addr) => self.read8(addr),
+            Some(addr) => self.read_modify(addr),
             None => self.a,
         };
 
@@ -433,6 +698,7 @@ impl<M: Mem> Cpu<M> {
             Some(addr) => self.write8(addr, result),
             None => self.a = result,
         };
+        result
     }
 
     fn asl(&mut self, addr: Option<u16>) {
@@ -459,65 +725,76 @@ impl<M: Mem> Cpu<M> {
         self.pc = addr;
     }
 
-    fn jsr(&mut self, addr: u16) {
-        let pc = self.pc.wrapping_sub(1);
+    /* The return address pushed is the last byte of the JSR */
+    fn jsr(&mut self) {
+        let low = self.next8() as u16;
+        self.stack_dummy_read();
+        let pc = self.pc;
         self.push16(pc);
-        self.pc = addr;
+        let high = self.read8(pc) as u16;
+        self.pc = high << 8 | low;
     }
 
     fn rts(&mut self) {
-        let pc = self.pull16().wrapping_add(1);
-        self.pc = pc;
+        self.stack_dummy_read();
+        let pc = self.pull16();
+        self.read8(pc);
+        self.pc = pc.wrapping_add(1);
     }
 
 
     /* Branches */
-    fn bcc(&mut self, addr: u16) {
-        if !self.get_flag(CARRY_FLAG) {
-            self.pc = addr;
+    fn branch(&mut self, taken: bool, addr: u16) {
+        if !taken {
+            return;
         }
+
+        let pc = self.pc;
+        self.read8(pc);
+        if (pc ^ addr) & 0xff00 != 0 {
+            self.read8(pc & 0xff00 | addr & 0x00ff);
+        }
+        self.pc = addr;
+    }
+
+    fn bcc(&mut self, addr: u16) {
+        let taken = !self.get_flag(CARRY_FLAG);
+        self.branch(taken, addr);
     }
 
     fn bcs(&mut self, addr: u16) {
-        if self.get_flag(CARRY_FLAG) {
-            self.pc = addr;
-        }
+        let taken = self.get_flag(CARRY_FLAG);
+        self.branch(taken, addr);
     }
 
     fn beq(&mut self, addr: u16) {
-        if self.get_flag(ZERO_FLAG) {
-            self.pc = addr;
-        }
+        let taken = self.get_flag(ZERO_FLAG);
+        self.branch(taken, addr);
     }
 
     fn bmi(&mut self, addr: u16) {
-        if self.get_flag(NEGATIVE_FLAG) {
-            self.pc = addr;
-        }
+        let taken = self.get_flag(NEGATIVE_FLAG);
+        self.branch(taken, addr);
     }
 
     fn bne(&mut self, addr: u16) {
-        if !self.get_flag(ZERO_FLAG) {
-            self.pc = addr;
-        }
+        let taken = !self.get_flag(ZERO_FLAG);
+        self.branch(taken, addr);
     }
 
     fn bpl(&mut self, addr: u16) {
-        if !self.get_flag(NEGATIVE_FLAG) {
-            self.pc = addr;
-        }
+        let taken = !self.get_flag(NEGATIVE_FLAG);
+        self.branch(taken, addr);
     }
 
     fn bvc(&mut self, addr: u16) {
-        if !self.get_flag(OVERFLOW_FLAG) {
-            self.pc = addr;
-        }
+        let taken = !self.get_flag(OVERFLOW_FLAG);
+        self.branch(taken, addr);
     }
 
     fn bvs(&mut self, addr: u16) {
-        if self.get_flag(OVERFLOW_FLAG) {
-            self.pc = addr;
-        }
+        let taken = self.get_flag(OVERFLOW_FLAG);
+        self.branch(taken, addr);
     }
 
 
@@ -552,21 +829,195 @@ impl<M: Mem> Cpu<M> {
 
 
     /* System Functions */
+    /* BRK skips a padding byte, so RTI returns two bytes after it */
     fn brk(&mut self) {
-        self.jsr(0xFFFE);
-        self.php();
+        let pc = self.pc.wrapping_add(1);
+        self.push16(pc);
+        let status = self.get_status();
+        self.push8(status);
         self.sei();
+        self.pc = self.read16(IRQ_VECTOR);
     }
 
-    fn nop(&self) {}
+    fn nop(&mut self, addr: Option<u16>) {
+        if let Some(addr) = addr {
+            self.read8(addr);
+        }
+    }
 
     fn rti(&mut self) {
-        self.plp();
+        self.stack_dummy_read();
+        let status = self.pull8();
+        self.set_status(status);
         self.pc = self.pull16();
     }
 
+    /* Unofficial */
+    fn lax(&mut self, addr: u16) {
+        let val = self.read8(addr);
+        self.a = val;
+        self.x = val;
+        self.set_zn(val);
+    }
+
+    fn sax(&mut self, addr: u16) {
+        let val = self.a & self.x;
+        self.write8(addr, val);
+    }
+
+    /* DEC then CMP */
+    fn dcp(&mut self, addr: u16) {
+        let result = self.read_modify(addr).wrapping_sub(1);
+        self.write8(addr, result);
+        let a = self.a;
+        self.cmp_base(a, result);
+    }
+
+    /* INC then SBC */
+    fn isc(&mut self, addr: u16) {
+        let result = self.read_modify(addr).wrapping_add(1);
+        self.write8(addr, result);
+        self.sbc_base(result);
+    }
+
+    /* ASL then ORA */
+    fn slo(&mut self, addr: u16) {
+        let result = self.a | self.shl_base(Some(addr), false);
+        self.a = result;
+        self.set_zn(result);
+    }
+
+    /* ROL then AND */
+    fn rla(&mut self, addr: u16) {
+        let c = self.get_flag(CARRY_FLAG);
+        let result = self.a & self.shl_base(Some(addr), c);
+        self.a = result;
+        self.set_zn(result);
+    }
+
+    /* LSR then EOR */
+    fn sre(&mut self, addr: u16) {
+        let result = self.a ^ self.shr_base(Some(addr), false);
+        self.a = result;
+        self.set_zn(result);
+    }
+
+    /* ROR then ADC */
+    fn rra(&mut self, addr: u16) {
+        let c = self.get_flag(CARRY_FLAG);
+        let val = self.shr_base(Some(addr), c);
+        self.adc_base(val);
+    }
+
+    /* AND with the carry set like ASL would */
+    fn anc(&mut self, addr: u16) {
+        self.and(addr);
+        let negative = self.get_flag(NEGATIVE_FLAG);
+        self.set_flag(CARRY_FLAG, negative);
+    }
+
+    /* AND then LSR A */
+    fn alr(&mut self, addr: u16) {
+        self.and(addr);
+        self.shr_base(None, false);
+    }
+
+    /* AND then ROR A, with C and V taken from bits 6 and 5 of the result */
+    fn arr(&mut self, addr: u16) {
+        let c = self.get_flag(CARRY_FLAG);
+        let result = (self.a & self.read8(addr)) >> 1 | (c as u8) << 7;
+        self.a = result;
+        self.set_zn(result);
+        self.set_flag(CARRY_FLAG, result & 0x40 != 0);
+        self.set_flag(OVERFLOW_FLAG, (result ^ result << 1) & 0x40 != 0);
+    }
+
+    /* X = (A & X) - imm, flags set like CMP */
+    fn axs(&mut self, addr: u16) {
+        let a = self.a & self.x;
+        let b = self.read8(addr);
+        self.x = a.wrapping_sub(b);
+        self.cmp_base(a, b);
+    }
+
+    fn las(&mut self, addr: u16) {
+        let result = self.read8(addr) & self.sp;
+        self.a = result;
+        self.x = result;
+        self.sp = result;
+        self.set_zn(result);
+    }
+
+    /* Same as BRK, but without the B flag and the PC isn't advanced */
+    fn interrupt(&mut self, vector: u16) {
+        let pc = self.pc;
+        self.read8(pc);
+        self.read8(pc);
+        self.push16(pc);
+        let status = self.get_status() & !0x10;
+        self.push8(status);
+        self.sei();
+        self.pc = self.read16(vector);
+    }
+
+    /* An interrupt with the stack writes turned into reads */
+    fn reset_sequence(&mut self) {
+        let pc = self.pc;
+        self.read8(pc);
+        self.read8(pc);
+        for _ in 0..3 {
+            let sp = self.sp;
+            self.read8(0x0100 | sp as u16);
+            self.sp = sp.wrapping_sub(1);
+        }
+        self.sei();
+        self.pc = self.read16(RESET_VECTOR);
+        self.nmi_pending = false;
+        self.nmi_latched = false;
+        self.irq_pending = false;
+        self.irq_latched = false;
+    }
+
     /* Memory helpers */
-    fn read16(&self, addr: u16) -> u16 {
+
+    /* Every bus access takes one CPU cycle */
+    fn read8(&mut self, addr: u16) -> u8 {
+        self.clock += self.mem.dma();
+        self.mem.tick();
+        self.clock += 1;
+        let val = self.mem.read8(addr);
+        self.debugger.access(addr, val, false);
+        self.poll_interrupts();
+        val
+    }
+
+    fn write8(&mut self, addr: u16, val: u8) {
+        self.mem.tick();
+        self.clock += 1;
+        self.debugger.access(addr, val, true);
+        self.mem.write8(addr, val);
+        self.poll_interrupts();
+    }
+
+    /* NMI is an edge, so it stays pending until it's serviced, IRQ is a level */
+    fn poll_interrupts(&mut self) {
+        self.nmi_latched = self.nmi_pending;
+        if self.mem.poll_nmi() {
+            self.nmi_pending = true;
+        }
+
+        self.irq_latched = self.irq_pending;
+        self.irq_pending = self.mem.poll_irq() && !self.get_flag(IRQ_FLAG);
+    }
+
+    /* Read-modify-write instructions write back the unmodified value first */
+    fn read_modify(&mut self, addr: u16) -> u8 {
+        let val = self.read8(addr);
+        self.write8(addr, val);
+        val
+    }
+
+    fn read16(&mut self, addr: u16) -> u16 {
         let low = self.read8(addr) as u16;
         let high = self.read8(addr.wrapping_add(1)) as u16;
         high << 8 | low
@@ -578,19 +1029,12 @@ impl<M: Mem> Cpu<M> {
      *
      * This is the cause of the indirect JMP bug on the 6502
      */
-    fn read16_zero_page(&self, addr: u16) -> u16 {
+    fn read16_zero_page(&mut self, addr: u16) -> u16 {
         let low = self.read8(addr) as u16;
         let high = self.read8(addr & 0xFF00 | (addr as u8).wrapping_add(1) as u16) as u16;
         high << 8 | low
     }
 
-    fn write16(&mut self, addr: u16, val: u16) {
-        let low = val as u8;
-        let high = (val >> 8) as u8;
-        self.write8(addr, low);
-        self.write8(addr.wrapping_add(1), high);
-    }
-
     fn next8(&mut self) -> u8 {
         let val = self.read8(self.pc);
         self.pc = self.pc.wrapping_add(1);
@@ -618,16 +1062,20 @@ impl<M: Mem> Cpu<M> {
     }
 
     fn pull16(&mut self) -> u16 {
-        let sp = self.sp.wrapping_add(1);
-        let val = self.read16(0x0100 + sp as u16);
-        self.sp = self.sp.wrapping_add(2);
-        val
+        let low = self.pull8() as u16;
+        let high = self.pull8() as u16;
+        high << 8 | low
     }
 
     fn push16(&mut self, val: u16) {
-        let sp = self.sp.wrapping_sub(1);
-        self.write16(0x0100 + sp as u16, val);
-        self.sp = self.sp.wrapping_sub(2);
+        self.push8((val >> 8) as u8);
+        self.push8(val as u8);
+    }
+
+    /* Pulls spend a cycle reading the stack before incrementing SP */
+    fn stack_dummy_read(&mut self) {
+        let sp = self.sp;
+        self.read8(0x0100 + sp as u16);
     }
 
     /* Flag helpers */
@@ -668,12 +1116,34 @@ impl<M: Mem> Cpu<M> {
         for line in stdin.lock().lines() {
             let line = line.unwrap();
 
-            let mut input = line.split(' ');
+            let mut input = line.split_whitespace();
             let command = input.next();
+            let args: Vec<&str> = input.collect();
 
             match command {
-                Some("reset") | Some("r") => self.reset(),
-                Some("step") | Some("") => {println!("{}", self.trace()); self.step();},
+                Some("reset") | Some("r") => self.soft_reset(),
+                Some("step") | Some("s") | None => {println!("{}", self.trace()); self.debug_step();},
+                Some("continue") | Some("c") => self.debug_continue(&args),
+                Some("break") | Some("b") => self.add_breakpoint(&args),
+                Some("watch") | Some("w") => self.add_watchpoint(&args),
+                Some("delete") | Some("d") => self.delete_point(&args),
+                Some("list") | Some("l") => for &(id, point) in self.debugger.points() {
+                    println!("{:3}  {}", id, point);
+                },
+                Some("mem") | Some("m") => self.dump_memory(&args),
+                Some("poke") => self.poke(&args),
+                Some("regs") => self.print_registers(),
+                Some("set") => self.set_registers(&args),
+                Some("stack") => self.print_stack(),
+                Some("disasm") | Some("u") => self.print_disassembly(&args),
+                Some("symbols") => match args.first().map(Symbols::load) {
+                    Some(Ok(symbols)) => {
+                        println!("Loaded {} symbols", symbols.len());
+                        self.debugger.set_symbols(symbols);
+                    },
+                    Some(Err(err)) => println!("Failed to load symbols: {}", err),
+                    None => println!("Usage: symbols FILE.nl"),
+                },
                 Some("test") | Some("t") => self.nestest(),
                 Some("trace") => println!("{}", self.trace()),
                 Some("quit") | Some("q") => break,
@@ -684,66 +1154,390 @@ impl<M: Mem> Cpu<M> {
         }
     }
 
-    fn nestest(&mut self) {
-        let file = File::open("test/nestest-mod.log").unwrap();
-        let reader = BufReader::new(file);
+    /* Runs one instruction, returns true if it set off a watchpoint */
+    fn debug_step(&mut self) -> bool {
+        let pc = self.pc;
+        self.debugger.take_hit();
+        self.step();
 
-        for (i, line) in reader.lines().enumerate() {
-            let line = line.unwrap();
+        match self.debugger.take_hit() {
+            Some(hit) => {
+                let access = if hit.write { "write" } else { "read" };
+                println!("Watchpoint {}: {} ${:04X} = ${:02X} at ${:04X}", hit.id, access, hit.addr, hit.val, pc);
+                true
+            },
+            None => false,
+        }
+    }
 
-            let trace = self.trace();
-            let expected = line.trim_right();
+    /* continue [COUNT], stopping after COUNT instructions if nothing's hit */
+    fn debug_continue(&mut self, args: &[&str]) {
+        let limit = match args.first() {
+            Some(count) => match count.parse() {
+                Ok(count) => count,
+                Err(_) => return println!("Usage: continue [COUNT]"),
+            },
+            None => CONTINUE_LIMIT,
+        };
 
-            if trace != expected {
-                println!("Test Failed (line {})", i + 1);
-                println!("Expected: {}", expected);
-                println!("Obtained: {}", trace);
+        let mut count = 0;
+        loop {
+            if count == limit {
+                println!("Stopped after {} instructions", count);
                 break;
             }
+            count += 1;
 
-            println!("{}", trace);
-            self.step();
+            if self.debug_step() {
+                break;
+            }
+
+            if let Some(id) = self.debugger.breakpoint(self.pc, |register| self.register(register)) {
+                println!("Breakpoint {} at ${:04X}", id, self.pc);
+                break;
+            }
         }
+        println!("{}", self.trace());
     }
 
-    fn trace(&self) -> String {
-        let addr = self.pc;
-        let opcode = self.read8(addr);
+    /* break ADDR [if CONDITION] */
+    fn add_breakpoint(&mut self, args: &[&str]) {
+        let addr = args.first().and_then(|addr| debugger::parse_number(addr));
+        let condition = match args.get(1) {
+            Some(&"if") => Condition::parse(&args[2..].join(" ")).map(Some),
+            Some(_) => None,
+            None => Some(None),
+        };
 
-        let mode = AddressingMode::from(opcode);
-        let instruction = Instruction::from(opcode);
+        match (addr, condition) {
+            (Some(addr), Some(condition)) => {
+                let id = self.debugger.add(Point::Break { addr, condition });
+                println!("Breakpoint {} at ${:04X}", id, addr);
+            },
+            _ => println!("Usage: break ADDR [if REG==VALUE]"),
+        }
+    }
+
+    /* watch ADDR|START-END [r|w|rw] */
+    fn add_watchpoint(&mut self, args: &[&str]) {
+        let range = args.first().and_then(|range| debugger::parse_range(range));
+        let access = args.get(1).map_or(Some(Access::ReadWrite), |access| Access::from_name(access));
+
+        match (range, access) {
+            (Some((start, end)), Some(access)) => {
+                let id = self.debugger.add(Point::Watch { start, end, access });
+                println!("Watchpoint {}", id);
+            },
+            _ => println!("Usage: watch ADDR|START-END [r|w|rw]"),
+        }
+    }
+
+    /* delete [ID], without an id everything is deleted */
+    fn delete_point(&mut self, args: &[&str]) {
+        match args.first().map(|id| id.parse()) {
+            None => self.debugger.clear(),
+            Some(Ok(id)) => if !self.debugger.delete(id) {
+                println!("No breakpoint or watchpoint {}", id);
+            },
+            Some(Err(_)) => println!("Usage: delete [ID]"),
+        }
+    }
 
-        let mut hex_repr = Vec::new();
-        for i in 0..mode.bytes() + 1 {
-            write!(&mut hex_repr, "{:02X} ", self.read8(addr + i as u16)).unwrap();
+    /* mem ADDR [LEN], peeked so dumping PPU registers doesn't disturb them */
+    fn dump_memory(&self, args: &[&str]) {
+        let addr = args.first().and_then(|addr| debugger::parse_number(addr));
+        let len = args.get(1).map_or(Some(0x40), |len| debugger::parse_number(len));
+
+        let (addr, len) = match (addr, len) {
+            (Some(addr), Some(len)) => (addr as usize, len as usize),
+            _ => return println!("Usage: mem ADDR [LEN]"),
+        };
+
+        let end = (addr + len).min(0x10000);
+        for line in (addr..end).step_by(16) {
+            print!("{:04X}:", line);
+            for addr in line..(line + 16).min(end) {
+                print!(" {:02X}", self.mem.peek8(addr as u16));
+            }
+            println!();
+        }
+    }
+
+    /* poke ADDR VAL, a real write so it has the usual side effects */
+    fn poke(&mut self, args: &[&str]) {
+        let addr = args.first().and_then(|addr| debugger::parse_number(addr));
+        let val = args.get(1).and_then(|val| debugger::parse_number(val));
+
+        match (addr, val) {
+            (Some(addr), Some(val)) if val <= 0xff => self.mem.write8(addr, val as u8),
+            _ => println!("Usage: poke ADDR VAL"),
+        }
+    }
+
+    /* P is shown the way trace shows it, B only exists on the stack */
+    fn print_registers(&self) {
+        let status = self.get_status() & !0x10;
+        let flags: String = "NV-BDIZC".chars().enumerate().map(|(i, flag)| {
+            if status & 0x80 >> i != 0 { flag } else { flag.to_ascii_lowercase() }
+        }).collect();
+
+        println!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}  {}  CYC:{}",
+                 self.a, self.x, self.y, status, self.sp, self.pc, flags, self.clock);
+    }
+
+    /* set A=10 X=20 PC=C000 */
+    fn set_registers(&mut self, args: &[&str]) {
+        let mut values = Vec::new();
+        for arg in args {
+            let mut sides = arg.splitn(2, '=');
+            let register = sides.next().and_then(Register::from_name);
+            let val = sides.next().and_then(debugger::parse_number);
+            match (register, val) {
+                (Some(register), Some(val)) => values.push((register, val)),
+                _ => return println!("Usage: set REG=VALUE..."),
+            }
+        }
+
+        for (register, val) in values {
+            self.set_register(register, val);
+        }
+        self.print_registers();
+    }
+
+    /*
+     * Shows the stack from SP up. A pushed address that points at the last
+     * byte of a JSR is a return address, RTS goes to the byte after it.
+     */
+    fn print_stack(&self) {
+        for sp in self.sp as u16 + 1..=0xff {
+            let addr = 0x0100 | sp;
+            let val = self.mem.peek8(addr);
+            print!("{:04X}: {:02X}", addr, val);
+
+            if sp < 0xff {
+                let ret = (self.mem.peek8(addr + 1) as u16) << 8 | val as u16;
+                let jsr = ret.wrapping_sub(2);
+                if self.mem.peek8(jsr) == 0x20 {
+                    let target = (self.mem.peek8(ret) as u16) << 8 | self.mem.peek8(jsr.wrapping_add(1)) as u16;
+                    print!("  return to ${:04X} (JSR ${:04X} at ${:04X})", ret.wrapping_add(1), target, jsr);
+                }
+            }
+            println!();
+        }
+    }
+
+    /* disasm [ADDR] [COUNT], from PC by default */
+    fn print_disassembly(&self, args: &[&str]) {
+        let addr = args.first().map_or(Some(self.pc), |addr| debugger::parse_number(addr));
+        let count = args.get(1).map_or(Some(10), |count| debugger::parse_number(count));
+
+        let (mut addr, count) = match (addr, count) {
+            (Some(addr), Some(count)) => (addr, count),
+            _ => return println!("Usage: disasm [ADDR] [COUNT]"),
+        };
+
+        let symbols = self.debugger.symbols();
+        for _ in 0..count {
+            if let Some(label) = symbols.get(addr) {
+                println!("{}:", label);
+            }
+
+            /* Effective addresses are only right for the instruction at PC */
+            let registers = if addr == self.pc { Some(Registers { x: self.x, y: self.y }) } else { None };
+            let line = disasm::disassemble(&self.mem, addr, registers, Some(symbols));
+            let marker = if addr == self.pc { '>' } else { ' ' };
+            let unofficial = if line.unofficial { '*' } else { ' ' };
+            println!("{}{:04X}  {:<9}{}{}", marker, addr, line.hex(), unofficial, line.text);
+            addr = addr.wrapping_add(line.len());
+        }
+    }
+
+    fn nestest(&mut self) {
+        let log = match fs::read_to_string("test/nestest.log") {
+            Ok(log) => log,
+            Err(err) => return println!("Failed to read test/nestest.log: {}", err),
+        };
+        if let Some(offset) = log.lines().next().and_then(|line| self.align_to_log(line)) {
+            println!("Moved the PPU {} dots to line up with the log", offset);
+        }
+
+        match self.compare_log(log.as_bytes(), true) {
+            Ok(Some(mismatch)) => {
+                println!("Test Failed (line {})", mismatch.line);
+                println!("Expected: {}", mismatch.expected);
+                println!("Obtained: {}", mismatch.obtained);
+            },
+            Ok(None) => println!("Test Passed"),
+            Err(err) => println!("Failed to read test/nestest.log: {}", err),
         }
+    }
+
+    /*
+     * Emulators power up at different points in the frame, so this moves the
+     * PPU to where `line` from a log has it. Returns how many dots it moved
+     * forward, None if the line or the memory map has no PPU position.
+     */
+    pub fn align_to_log(&mut self, line: &str) -> Option<i32> {
+        let (scanline, dot) = log_position(line)?;
+        let (old_scanline, old_dot) = self.mem.ppu_position()?;
+        self.mem.set_ppu_position(scanline, dot);
+        Some((scanline as i32 - old_scanline as i32) * ppu::DOTS as i32 + dot as i32 - old_dot as i32)
+    }
 
-        let display_repr = format!("{:?}", instruction);
+    /*
+     * Steps through `log` a line at a time, stopping at the first line the
+     * trace differs from. See align_to_log() for logs that start elsewhere
+     * in the frame.
+     */
+    pub fn compare_log<R: BufRead>(&mut self, log: R, print: bool) -> io::Result<Option<LogMismatch>> {
+        for (i, line) in log.lines().enumerate() {
+            let line = line?;
+            let trace = self.trace();
+            let expected = line.trim_end();
 
-        format!(
-            "{:04X}  {:<10}{:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
-            addr,
-            String::from_utf8(hex_repr).unwrap(),
-            display_repr,
+            if trace != expected {
+                return Ok(Some(LogMismatch {
+                    line: i + 1,
+                    expected: expected.to_string(),
+                    obtained: trace,
+                }));
+            }
+
+            if print {
+                println!("{}", trace);
+            }
+            self.step();
+        }
+        Ok(None)
+    }
+
+    /*
+     * A line in the format of Nintendulator's logs (and nestest.log), with
+     * the PPU's dot and scanline on the end if the memory map knows them.
+     */
+    pub fn trace(&self) -> String {
+        let registers = Registers { x: self.x, y: self.y };
+        let line = disasm::disassemble(&TraceMem(&self.mem), self.pc, Some(registers), None);
+
+        let mut trace = format!(
+            "{:04X}  {:<9}{}{:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            self.pc,
+            line.hex(),
+            if line.unofficial { '*' } else { ' ' },
+            line.text,
             self.a,
             self.x,
             self.y,
             self.get_status() & !0x10,
             self.sp,
-        )
+        );
+
+        if let Some((scanline, dot)) = self.mem.ppu_position() {
+            write!(trace, " CYC:{:>3} SL:{}", dot, scanline).unwrap();
+        }
+        trace
     }
 }
 
-/*
- * TODO(Future):
- * Method delegation: https://github.com/rust-lang/rfcs/pull/1406
- */
-impl<M: Mem> Mem for Cpu<M> {
-    fn read8(&self, addr: u16) -> u8 {
-        self.mem.read8(addr)
+/* The APU and I/O registers are mostly write-only, Nintendulator's logs show them as FF */
+struct TraceMem<'a, M: 'a>(&'a M);
+
+impl<'a, M: Mem> Mem for TraceMem<'a, M> {
+    fn peek8(&self, addr: u16) -> u8 {
+        match addr {
+            0x4000 ..= 0x401f => 0xff,
+            _ => self.0.peek8(addr),
+        }
     }
 
-    fn write8(&mut self, addr: u16, val: u8) {
-        self.mem.write8(addr, val)
+    fn write8(&mut self, _addr: u16, _val: u8) {}
+}
+
+/* The "CYC:  0 SL:241" on the end of a log line */
+fn log_position(line: &str) -> Option<(i16, u16)> {
+    let dot = line.split("CYC:").nth(1)?.split_whitespace().next()?.parse().ok()?;
+    let scanline = line.split("SL:").nth(1)?.split_whitespace().next()?.parse().ok()?;
+    Some((scanline, dot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem::FlatMem;
+
+    const N: u8 = NEGATIVE_FLAG;
+    const V: u8 = OVERFLOW_FLAG;
+    const Z: u8 = ZERO_FLAG;
+    const C: u8 = CARRY_FLAG;
+
+    /* Runs `opcode #val` with D and the given carry set, returning A and N, V, Z and C */
+    fn run<T: Variant>(variant: T, opcode: u8, a: u8, val: u8, carry: bool) -> (u8, u8) {
+        let mut mem = FlatMem::new();
+        mem.load(0x0200, &[opcode, val]);
+
+        let mut cpu = Cpu::with_variant(mem, variant);
+        cpu.set_pc(0x0200);
+        cpu.set_register(Register::A, a as u16);
+        cpu.set_register(Register::P, (DECIMAL_FLAG | if carry { C } else { 0 }) as u16);
+        cpu.step();
+        (cpu.register(Register::A) as u8, cpu.register(Register::P) as u8 & (N | V | Z | C))
+    }
+
+    /* A, the operand, carry in, then A and the flags out */
+    #[test]
+    fn adc_decimal() {
+        let cases = [
+            (0x00, 0x00, false, 0x00, Z),
+            (0x05, 0x05, false, 0x10, 0),
+            (0x12, 0x34, false, 0x46, 0),
+            (0x81, 0x92, false, 0x73, V | C),
+            (0x99, 0x99, true, 0x99, V | C),
+            /* Z comes from the binary sum, N and V from before the high digit's adjusted */
+            (0x99, 0x01, false, 0x00, N | C),
+            (0x58, 0x46, true, 0x05, N | V | C),
+            (0x79, 0x00, true, 0x80, N | V),
+            (0x50, 0x50, false, 0x00, N | V | C),
+            /* Digits above 9 */
+            (0x0a, 0x00, false, 0x10, 0),
+            (0x0f, 0x0f, false, 0x14, 0),
+            (0x1c, 0x1c, false, 0x3e, 0),
+            (0x9a, 0x00, false, 0x00, N | C),
+            (0xff, 0xff, true, 0x55, N | C),
+        ];
+        for &(a, val, carry, result, flags) in &cases {
+            assert_eq!(run(Nmos6502, 0x69, a, val, carry), (result, flags),
+                       "${:02X} + ${:02X} + {}", a, val, carry as u8);
+        }
+    }
+
+    #[test]
+    fn sbc_decimal() {
+        let cases = [
+            (0x00, 0x00, true, 0x00, Z | C),
+            (0x00, 0x01, true, 0x99, N),
+            (0x46, 0x12, true, 0x34, C),
+            (0x40, 0x13, true, 0x27, C),
+            (0x32, 0x02, false, 0x29, C),
+            (0x12, 0x21, true, 0x91, N),
+            (0x01, 0x01, false, 0x99, N),
+            /* The flags are all from the binary difference */
+            (0x80, 0x01, true, 0x79, V | C),
+            (0x00, 0xff, true, 0xab, 0),
+            /* Digits above 9 */
+            (0x0a, 0x00, true, 0x0a, C),
+            (0x1b, 0x0c, true, 0x09, C),
+            (0xc0, 0x01, true, 0xb9, N | C),
+            (0xff, 0xff, false, 0x99, N),
+        ];
+        for &(a, val, carry, result, flags) in &cases {
+            assert_eq!(run(Nmos6502, 0xe9, a, val, carry), (result, flags),
+                       "${:02X} - ${:02X} - {}", a, val, !carry as u8);
+        }
+    }
+
+    #[test]
+    fn no_decimal_on_2a03() {
+        assert_eq!(run(Ricoh2A03, 0x69, 0x09, 0x01, false), (0x0a, 0));
+        assert_eq!(run(Ricoh2A03, 0xe9, 0x10, 0x01, true), (0x0f, C));
     }
 }
@@ -0,0 +1,275 @@
+use disasm::Symbols;
+
+use std::fmt;
+
+/*
+ * Breakpoints and watchpoints for Cpu::interactive. Watchpoints are checked
+ * on every CPU bus access, so stack pushes and indirect pointer reads set
+ * them off too, not just the instruction's operand.
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    P,
+    SP,
+    PC,
+}
+
+impl Register {
+    pub fn from_name(name: &str) -> Option<Register> {
+        match name.to_ascii_uppercase().as_str() {
+            "A" => Some(Register::A),
+            "X" => Some(Register::X),
+            "Y" => Some(Register::Y),
+            "P" => Some(Register::P),
+            "SP" | "S" => Some(Register::SP),
+            "PC" => Some(Register::PC),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/* Longest operators first, so "<=" isn't taken for "<" */
+const OPERATORS: [(&str, Compare); 6] = [
+    ("==", Compare::Eq),
+    ("!=", Compare::Ne),
+    ("<=", Compare::Le),
+    (">=", Compare::Ge),
+    ("<", Compare::Lt),
+    (">", Compare::Gt),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub compare: Compare,
+    pub value: u16,
+}
+
+impl Condition {
+    /* Parses "A==0x10", "X != $FF" and so on */
+    pub fn parse(text: &str) -> Option<Condition> {
+        let text: String = text.split_whitespace().collect();
+        let &(op, compare) = OPERATORS.iter().find(|&&(op, _)| text.contains(op))?;
+        let mut sides = text.splitn(2, op);
+        let register = Register::from_name(sides.next()?)?;
+        let value = parse_number(sides.next()?)?;
+
+        Some(Condition {
+            register,
+            compare,
+            value,
+        })
+    }
+
+    pub fn eval(&self, value: u16) -> bool {
+        match self.compare {
+            Compare::Eq => value == self.value,
+            Compare::Ne => value != self.value,
+            Compare::Lt => value < self.value,
+            Compare::Le => value <= self.value,
+            Compare::Gt => value > self.value,
+            Compare::Ge => value >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = OPERATORS.iter().find(|&&(_, compare)| compare == self.compare).unwrap().0;
+        write!(f, "{:?}{}${:02X}", self.register, op, self.value)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn from_name(name: &str) -> Option<Access> {
+        match name {
+            "r" => Some(Access::Read),
+            "w" => Some(Access::Write),
+            "rw" => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+
+    fn matches(self, write: bool) -> bool {
+        match self {
+            Access::Read => !write,
+            Access::Write => write,
+            Access::ReadWrite => true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Point {
+    Break {
+        addr: u16,
+        condition: Option<Condition>,
+    },
+    /* Inclusive range */
+    Watch {
+        start: u16,
+        end: u16,
+        access: Access,
+    },
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Point::Break { addr, condition: None } => write!(f, "break ${:04X}", addr),
+            Point::Break { addr, condition: Some(condition) } => write!(f, "break ${:04X} if {}", addr, condition),
+            Point::Watch { start, end, access } if start == end => write!(f, "watch ${:04X} {:?}", start, access),
+            Point::Watch { start, end, access } => write!(f, "watch ${:04X}-${:04X} {:?}", start, end, access),
+        }
+    }
+}
+
+/* A watched access, recorded while the instruction runs */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub addr: u16,
+    pub val: u8,
+    pub write: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    points: Vec<(usize, Point)>,
+    next_id: usize,
+    watching: bool,
+    hit: Option<WatchHit>,
+    symbols: Symbols,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            next_id: 1,
+            ..Debugger::default()
+        }
+    }
+
+    /* Returns the id to delete it with */
+    pub fn add(&mut self, point: Point) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.points.push((id, point));
+        self.update_watching();
+        id
+    }
+
+    pub fn delete(&mut self, id: usize) -> bool {
+        let len = self.points.len();
+        self.points.retain(|&(point_id, _)| point_id != id);
+        self.update_watching();
+        self.points.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.update_watching();
+    }
+
+    pub fn points(&self) -> &[(usize, Point)] {
+        &self.points
+    }
+
+    /* The first breakpoint at `pc` whose condition holds, `register` gives the current values */
+    pub fn breakpoint<F: Fn(Register) -> u16>(&self, pc: u16, register: F) -> Option<usize> {
+        self.points.iter().find(|&&(_, point)| match point {
+            Point::Break { addr, condition } => addr == pc && condition.is_none_or(|condition| {
+                condition.eval(register(condition.register))
+            }),
+            Point::Watch { .. } => false,
+        }).map(|&(id, _)| id)
+    }
+
+    /* Called on every CPU bus access, only the first hit is kept */
+    pub fn access(&mut self, addr: u16, val: u8, write: bool) {
+        if !self.watching || self.hit.is_some() {
+            return;
+        }
+
+        for &(id, point) in &self.points {
+            if let Point::Watch { start, end, access } = point {
+                if (start..=end).contains(&addr) && access.matches(write) {
+                    self.hit = Some(WatchHit {
+                        id,
+                        addr,
+                        val,
+                        write,
+                    });
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn take_hit(&mut self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    /* Labels for disassembly */
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    fn update_watching(&mut self) {
+        self.watching = self.points.iter().any(|&(_, point)| matches!(point, Point::Watch { .. }));
+    }
+}
+
+/* Numbers are hex by default, "$" and "0x" prefixes are allowed and "#" is decimal */
+pub fn parse_number(text: &str) -> Option<u16> {
+    let text = text.trim();
+    if let Some(decimal) = text.strip_prefix('#') {
+        return decimal.parse().ok();
+    }
+
+    let hex = text.strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+    u16::from_str_radix(hex, 16).ok()
+}
+
+/* Parses "0300" or "0300-03ff" */
+pub fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let mut bounds = text.splitn(2, '-');
+    let start = parse_number(bounds.next()?)?;
+    let end = match bounds.next() {
+        Some(end) => parse_number(end)?,
+        None => start,
+    };
+
+    if end < start {
+        return None;
+    }
+    Some((start, end))
+}
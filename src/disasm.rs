@@ -0,0 +1,246 @@
+use mem::Mem;
+use opcode::{self, AddressingMode, Instruction};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/*
+ * 6502 disassembler, formatted like Nintendulator's traces (and so
+ * nestest.log): operands in hex, effective addresses after "@" and the
+ * value there after "=". Unofficial opcodes get a "*" in front.
+ *
+ *   LDA ($89),Y = 0300 @ 0300 = 89
+ *   *NOP $A9 = 00
+ *
+ * Memory is read with peek8, so disassembling never disturbs anything.
+ */
+
+/* Index registers, for working out effective addresses */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub x: u8,
+    pub y: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Disassembly {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub unofficial: bool,
+    /* The mnemonic and operand, without the "*" */
+    pub text: String,
+}
+
+impl Disassembly {
+    /* The instruction's bytes in hex, "4C F5 C5" */
+    pub fn hex(&self) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        bytes.join(" ")
+    }
+
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.unofficial {
+            write!(f, "*")?;
+        }
+        write!(f, "{}", self.text)
+    }
+}
+
+/*
+ * Labels for addresses, from FCEUX .nl files: one "$C000#Name#Comment"
+ * line per label, the comment is optional.
+ */
+#[derive(Debug, Default)]
+pub struct Symbols {
+    labels: HashMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn parse(text: &str) -> io::Result<Symbols> {
+        let mut symbols = Symbols::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split('#');
+            let addr = fields.next().and_then(|addr| u16::from_str_radix(addr.trim_start_matches('$'), 16).ok());
+            let name = fields.next().map(str::trim).filter(|name| !name.is_empty());
+            match (addr, name) {
+                (Some(addr), Some(name)) => symbols.insert(addr, name),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("bad symbol on line {}", i + 1))),
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Symbols> {
+        Symbols::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.labels.insert(addr, name.to_string());
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+}
+
+/*
+ * Disassembles the instruction at `addr`. The "@"/"=" annotations need the
+ * index registers, so they're left out when `registers` is None.
+ */
+pub fn disassemble<M: Mem>(mem: &M, addr: u16, registers: Option<Registers>, symbols: Option<&Symbols>) -> Disassembly {
+    let opcode = mem.peek8(addr);
+    let mode = AddressingMode::from(opcode);
+    let instruction = Instruction::from(opcode);
+
+    let bytes: Vec<u8> = (0..mode.bytes() as u16 + 1).map(|i| mem.peek8(addr.wrapping_add(i))).collect();
+    let operand8 = bytes.get(1).cloned().unwrap_or(0);
+    let operand16 = (bytes.get(2).cloned().unwrap_or(0) as u16) << 8 | operand8 as u16;
+
+    let name = |addr: u16, width: usize| match symbols.and_then(|symbols| symbols.get(addr)) {
+        Some(label) => label.to_string(),
+        None => format!("${:01$X}", addr, width),
+    };
+    let peek16 = |addr: u16, next: u16| (mem.peek8(next) as u16) << 8 | mem.peek8(addr) as u16;
+    /* Zero page pointers wrap around within the zero page */
+    let peek16_zero_page = |addr: u8| peek16(addr as u16, addr.wrapping_add(1) as u16);
+
+    let operand = match mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => String::from("A"),
+        AddressingMode::Immediate => format!("#${:02X}", operand8),
+        AddressingMode::Relative => {
+            let target = addr.wrapping_add(2).wrapping_add(operand8 as i8 as u16);
+            name(target, 4)
+        },
+        AddressingMode::ZeroPage => {
+            let text = name(operand8 as u16, 2);
+            match registers {
+                Some(_) => format!("{} = {:02X}", text, mem.peek8(operand8 as u16)),
+                None => text,
+            }
+        },
+        AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+            let (register, index) = match mode {
+                AddressingMode::ZeroPageX => ("X", registers.map(|registers| registers.x)),
+                _ => ("Y", registers.map(|registers| registers.y)),
+            };
+
+            let text = format!("{},{}", name(operand8 as u16, 2), register);
+            match index {
+                Some(index) => {
+                    let effective = operand8.wrapping_add(index) as u16;
+                    format!("{} @ {:02X} = {:02X}", text, effective, mem.peek8(effective))
+                },
+                None => text,
+            }
+        },
+        AddressingMode::Absolute => {
+            let text = name(operand16, 4);
+            match (instruction, registers) {
+                (Instruction::JMP, _) | (Instruction::JSR, _) | (_, None) => text,
+                (_, Some(_)) => format!("{} = {:02X}", text, mem.peek8(operand16)),
+            }
+        },
+        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+            let (register, index) = match mode {
+                AddressingMode::AbsoluteX => ("X", registers.map(|registers| registers.x)),
+                _ => ("Y", registers.map(|registers| registers.y)),
+            };
+
+            let text = format!("{},{}", name(operand16, 4), register);
+            match index {
+                Some(index) => {
+                    let effective = operand16.wrapping_add(index as u16);
+                    format!("{} @ {:04X} = {:02X}", text, effective, mem.peek8(effective))
+                },
+                None => text,
+            }
+        },
+        /*
+         * This shows the word stored at the operand, like Nintendulator does.
+         * It's not where JMP ($xxFF) goes, that reads the high byte from $xx00.
+         */
+        AddressingMode::Indirect => {
+            let text = format!("({})", name(operand16, 4));
+            match registers {
+                Some(_) => format!("{} = {:04X}", text, peek16(operand16, operand16.wrapping_add(1))),
+                None => text,
+            }
+        },
+        AddressingMode::IndexedIndirect => {
+            let text = format!("({},X)", name(operand8 as u16, 2));
+            match registers {
+                Some(registers) => {
+                    let pointer = operand8.wrapping_add(registers.x);
+                    let effective = peek16_zero_page(pointer);
+                    format!("{} @ {:02X} = {:04X} = {:02X}", text, pointer, effective, mem.peek8(effective))
+                },
+                None => text,
+            }
+        },
+        AddressingMode::IndirectIndexed => {
+            let text = format!("({}),Y", name(operand8 as u16, 2));
+            match registers {
+                Some(registers) => {
+                    let base = peek16_zero_page(operand8);
+                    let effective = base.wrapping_add(registers.y as u16);
+                    format!("{} = {:04X} @ {:04X} = {:02X}", text, base, effective, mem.peek8(effective))
+                },
+                None => text,
+            }
+        },
+    };
+
+    let mnemonic = mnemonic(instruction);
+    let text = if operand.is_empty() {
+        mnemonic
+    } else {
+        format!("{} {}", mnemonic, operand)
+    };
+
+    Disassembly {
+        addr,
+        bytes,
+        unofficial: opcode::is_unofficial(opcode),
+        text,
+    }
+}
+
+/* Nintendulator calls ISC "ISB" */
+fn mnemonic(instruction: Instruction) -> String {
+    match instruction {
+        Instruction::ISC => String::from("ISB"),
+        instruction => format!("{:?}", instruction),
+    }
+}
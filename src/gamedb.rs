@@ -0,0 +1,65 @@
+use region::Region;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/*
+ * Per-game settings for things iNES headers don't record, keyed by the
+ * CRC32 of the PRG and CHR ROM (see Rom::crc32). The text format has one
+ * game per line: the CRC in hex, the region, then an optional name.
+ *
+ *   # crc32   region  name
+ *   1234abcd  pal     Some Game (Europe)
+ */
+
+pub struct GameDb {
+    regions: HashMap<u32, Region>,
+}
+
+impl GameDb {
+    pub fn new() -> GameDb {
+        GameDb {
+            regions: HashMap::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> io::Result<GameDb> {
+        let mut db = GameDb::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let crc = fields.next().and_then(|crc| u32::from_str_radix(crc, 16).ok());
+            let region = fields.next().and_then(Region::from_name);
+            match (crc, region) {
+                (Some(crc), Some(region)) => db.insert(crc, region),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("bad game database entry on line {}", i + 1))),
+            }
+        }
+        Ok(db)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<GameDb> {
+        GameDb::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn insert(&mut self, crc: u32, region: Region) {
+        self.regions.insert(crc, region);
+    }
+
+    pub fn region(&self, crc: u32) -> Option<Region> {
+        self.regions.get(&crc).cloned()
+    }
+}
+
+impl Default for GameDb {
+    fn default() -> GameDb {
+        GameDb::new()
+    }
+}
@@ -0,0 +1,312 @@
+use cpu::{Cpu, Variant};
+use debugger::Register;
+use json::{self, Value};
+use mem::{BusCycle, FlatMem, Mem};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+ * Runs Tom Harte's single step CPU tests. There's one JSON file per
+ * opcode, each a list of cases like:
+ *
+ *   {
+ *     "name": "b1 28 b5",
+ *     "initial": { "pc": 59082, "s": 39, "a": 57, "x": 33, "y": 174, "p": 96,
+ *                  "ram": [[59082, 177], [59083, 40], ...] },
+ *     "final": { ... },
+ *     "cycles": [[59082, 177, "read"], [59083, 40, "read"], ...]
+ *   }
+ *
+ * Each case runs one instruction on a FlatMem and checks the registers,
+ * RAM and every bus access. B and bit 5 of P aren't real flags, so they're
+ * left out of the comparison.
+ *
+ * References:
+ *  - https://github.com/SingleStepTests/ProcessorTests/tree/main/nes6502
+ */
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(json::Error),
+    /* Valid JSON, but not a test case, with what was wrong */
+    Format(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<json::Error> for Error {
+    fn from(err: json::Error) -> Self {
+        Error::Json(err)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct State {
+    pub pc: u16,
+    pub s: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub ram: Vec<(u16, u8)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String,
+    pub initial: State,
+    pub expected: State,
+    pub cycles: Vec<BusCycle>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure {
+    pub name: String,
+    pub message: String,
+}
+
+/* The results for one file */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub total: usize,
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.total - self.failures.len()
+    }
+}
+
+pub fn parse(text: &str) -> Result<Vec<TestCase>, Error> {
+    let cases = json::parse(text)?;
+    let cases = cases.as_array().ok_or_else(|| format_error("expected a list of tests"))?;
+    cases.iter().map(parse_case).collect()
+}
+
+/* Runs one case on `cpu`, which is reused so RAM only needs allocating once */
+pub fn run_case<V: Variant>(cpu: &mut Cpu<FlatMem, V>, case: &TestCase) -> Result<(), String> {
+    let initial = &case.initial;
+    cpu.set_register(Register::PC, initial.pc);
+    cpu.set_register(Register::SP, initial.s as u16);
+    cpu.set_register(Register::A, initial.a as u16);
+    cpu.set_register(Register::X, initial.x as u16);
+    cpu.set_register(Register::Y, initial.y as u16);
+    cpu.set_register(Register::P, initial.p as u16);
+    for &(addr, val) in &initial.ram {
+        cpu.mem_mut().load(addr, &[val]);
+    }
+
+    cpu.mem_mut().record_cycles(true);
+    cpu.step();
+
+    let expected = &case.expected;
+    let registers = [
+        ("PC", Register::PC, expected.pc),
+        ("S", Register::SP, expected.s as u16),
+        ("A", Register::A, expected.a as u16),
+        ("X", Register::X, expected.x as u16),
+        ("Y", Register::Y, expected.y as u16),
+        ("P", Register::P, expected.p as u16 | 0x30),
+    ];
+    for &(name, register, val) in &registers {
+        let obtained = cpu.register(register);
+        if obtained != val {
+            return Err(format!("{} is ${:02X}, expected ${:02X}", name, obtained, val));
+        }
+    }
+
+    for &(addr, val) in &expected.ram {
+        let obtained = cpu.mem().peek8(addr);
+        if obtained != val {
+            return Err(format!("${:04X} is ${:02X}, expected ${:02X}", addr, obtained, val));
+        }
+    }
+
+    let cycles = cpu.mem().cycles();
+    for (i, expected) in case.cycles.iter().enumerate() {
+        match cycles.get(i) {
+            Some(cycle) if cycle == expected => {},
+            Some(cycle) => return Err(format!("cycle {} was {}, expected {}", i + 1, describe(cycle), describe(expected))),
+            None => return Err(format!("took {} cycles, expected {}", cycles.len(), case.cycles.len())),
+        }
+    }
+    if cycles.len() != case.cycles.len() {
+        return Err(format!("took {} cycles, expected {}", cycles.len(), case.cycles.len()));
+    }
+
+    Ok(())
+}
+
+pub fn run_file<P: AsRef<Path>>(path: P) -> Result<Report, Error> {
+    let cases = parse(&fs::read_to_string(path)?)?;
+    let mut cpu = Cpu::new(FlatMem::new());
+    let mut report = Report::default();
+
+    for case in &cases {
+        report.total += 1;
+        if let Err(message) = run_case(&mut cpu, case) {
+            report.failures.push(Failure {
+                name: case.name.clone(),
+                message,
+            });
+        }
+    }
+    Ok(report)
+}
+
+/* Runs every .json file in `dir`, in name order, so "00.json" to "ff.json" */
+pub fn run_dir<P: AsRef<Path>>(dir: P) -> io::Result<Vec<(PathBuf, Result<Report, Error>)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_json = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+        if is_json {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    Ok(files.into_iter().map(|file| {
+        let result = run_file(&file);
+        (file, result)
+    }).collect())
+}
+
+fn describe(cycle: &BusCycle) -> String {
+    let access = if cycle.write { "write" } else { "read" };
+    format!("{} ${:02X} at ${:04X}", access, cycle.val, cycle.addr)
+}
+
+fn format_error(what: &str) -> Error {
+    Error::Format(what.to_string())
+}
+
+fn parse_case(case: &Value) -> Result<TestCase, Error> {
+    let name = case.get("name").and_then(Value::as_str).ok_or_else(|| format_error("missing name"))?;
+    let field = |key: &str| case.get(key).ok_or_else(|| Error::Format(format!("{}: missing {}", name, key)));
+
+    let cycles = field("cycles")?.as_array().ok_or_else(|| format_error("cycles isn't a list"))?;
+    let cycles = cycles.iter().map(|cycle| {
+        let cycle = cycle.as_array().filter(|cycle| cycle.len() == 3);
+        let cycle = cycle.ok_or_else(|| Error::Format(format!("{}: bad cycle", name)))?;
+        let write = match cycle[2].as_str() {
+            Some("read") => false,
+            Some("write") => true,
+            _ => return Err(Error::Format(format!("{}: bad cycle type", name))),
+        };
+
+        Ok(BusCycle {
+            addr: number(&cycle[0], 0xffff, name)? as u16,
+            val: number(&cycle[1], 0xff, name)? as u8,
+            write,
+        })
+    }).collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(TestCase {
+        name: name.to_string(),
+        initial: parse_state(field("initial")?, name)?,
+        expected: parse_state(field("final")?, name)?,
+        cycles,
+    })
+}
+
+fn parse_state(state: &Value, name: &str) -> Result<State, Error> {
+    let register = |key: &str, max: u64| {
+        let val = state.get(key).ok_or_else(|| Error::Format(format!("{}: missing {}", name, key)))?;
+        number(val, max, name)
+    };
+
+    let ram = state.get("ram").and_then(Value::as_array).ok_or_else(|| Error::Format(format!("{}: missing ram", name)))?;
+    let ram = ram.iter().map(|entry| match entry.as_array() {
+        Some([addr, val]) => Ok((number(addr, 0xffff, name)? as u16, number(val, 0xff, name)? as u8)),
+        _ => Err(Error::Format(format!("{}: bad ram entry", name))),
+    }).collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(State {
+        pc: register("pc", 0xffff)? as u16,
+        s: register("s", 0xff)? as u8,
+        a: register("a", 0xff)? as u8,
+        x: register("x", 0xff)? as u8,
+        y: register("y", 0xff)? as u8,
+        p: register("p", 0xff)? as u8,
+        ram,
+    })
+}
+
+fn number(val: &Value, max: u64, name: &str) -> Result<u64, Error> {
+    val.as_u64()
+        .filter(|&val| val <= max)
+        .ok_or_else(|| Error::Format(format!("{}: {:?} is out of range", name, val)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* LDA #$80 at $1000, which sets N and clears Z */
+    const LDA_IMMEDIATE: &str = r#"[{
+        "name": "a9 80 a1",
+        "initial": { "pc": 4096, "s": 253, "a": 1, "x": 2, "y": 3, "p": 38,
+                     "ram": [[4096, 169], [4097, 128], [4098, 161]] },
+        "final": { "pc": 4098, "s": 253, "a": 128, "x": 2, "y": 3, "p": 164,
+                   "ram": [[4096, 169], [4097, 128], [4098, 161]] },
+        "cycles": [[4096, 169, "read"], [4097, 128, "read"]]
+    }]"#;
+
+    #[test]
+    fn parse_lda_immediate() {
+        let cases = parse(LDA_IMMEDIATE).unwrap();
+        assert_eq!(cases.len(), 1);
+        assert_eq!(cases[0].name, "a9 80 a1");
+        assert_eq!(cases[0].initial.pc, 0x1000);
+        assert_eq!(cases[0].expected.p, 0xa4);
+        assert_eq!(cases[0].expected.ram[1], (0x1001, 0x80));
+        assert_eq!(cases[0].cycles[1], BusCycle { addr: 0x1001, val: 0x80, write: false });
+    }
+
+    #[test]
+    fn bad_case() {
+        match parse(r#"[{"name": "x", "initial": {}, "final": {}, "cycles": []}]"#) {
+            Err(Error::Format(message)) => assert_eq!(message, "x: missing ram"),
+            other => panic!("expected a format error, got {:?}", other),
+        }
+        match parse(&LDA_IMMEDIATE.replace("\"pc\": 4098", "\"pc\": 65536")) {
+            Err(Error::Format(_)) => {},
+            other => panic!("expected a format error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn run_lda_immediate() {
+        let case = &parse(LDA_IMMEDIATE).unwrap()[0];
+        let mut cpu = Cpu::new(FlatMem::new());
+        assert_eq!(run_case(&mut cpu, case), Ok(()));
+    }
+
+    #[test]
+    fn report_mismatches() {
+        let case = &parse(LDA_IMMEDIATE).unwrap()[0];
+
+        let mut wrong = case.clone();
+        wrong.expected.a = 0x7f;
+        assert_eq!(run_case(&mut Cpu::new(FlatMem::new()), &wrong), Err(String::from("A is $80, expected $7F")));
+
+        let mut wrong = case.clone();
+        wrong.cycles[1].val = 0x00;
+        assert_eq!(run_case(&mut Cpu::new(FlatMem::new()), &wrong),
+                   Err(String::from("cycle 2 was read $80 at $1001, expected read $00 at $1001")));
+
+        let mut wrong = case.clone();
+        wrong.cycles.push(BusCycle { addr: 0x1002, val: 0xa1, write: false });
+        assert_eq!(run_case(&mut Cpu::new(FlatMem::new()), &wrong), Err(String::from("took 2 cycles, expected 3")));
+    }
+}
+
@@ -0,0 +1,108 @@
+use crc::crc32;
+
+use std::path::Path;
+
+/*
+ * Minimal image encoders for screenshots, so there's no need for an image
+ * library. PNGs are written with uncompressed deflate blocks.
+ *
+ * References:
+ *  - https://www.w3.org/TR/PNG/
+ *  - https://tools.ietf.org/html/rfc1950
+ *  - https://tools.ietf.org/html/rfc1951
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl ImageFormat {
+    /* Guesses the format from a file extension, defaulting to PNG */
+    pub fn from_path<P: AsRef<Path>>(path: P) -> ImageFormat {
+        let extension = path.as_ref().extension().and_then(|extension| extension.to_str());
+        match extension {
+            Some(extension) if extension.eq_ignore_ascii_case("ppm") => ImageFormat::Ppm,
+            _ => ImageFormat::Png,
+        }
+    }
+}
+
+/* `rgb` holds 3 bytes per pixel, rows top to bottom */
+pub fn encode_ppm(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    out.extend_from_slice(&rgb[..width * height * 3]);
+    out
+}
+
+/* `text` is written as tEXt chunks of keyword/value pairs */
+pub fn encode_png(width: usize, height: usize, rgb: &[u8], text: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    /* 8 bits per channel, RGB, deflate, no filtering, no interlacing */
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut out, b"IHDR", &header);
+
+    for &(keyword, value) in text {
+        let mut data = keyword.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(value.as_bytes());
+        write_chunk(&mut out, b"tEXt", &data);
+    }
+
+    /* Every row starts with its filter type, 0 for none */
+    let row = width * 3;
+    let mut raw = Vec::with_capacity((row + 1) * height);
+    for y in 0..height {
+        raw.push(0);
+        raw.extend_from_slice(&rgb[y * row..(y + 1) * row]);
+    }
+    write_chunk(&mut out, b"IDAT", &zlib_store(&raw));
+
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/* A zlib stream made of stored deflate blocks, which hold up to 65535 bytes each */
+fn zlib_store(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
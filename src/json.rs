@@ -0,0 +1,346 @@
+use std::str;
+
+/*
+ * Just enough JSON to load test suites, so there's no need for serde.
+ * Objects keep their keys in order, numbers are all f64.
+ *
+ * References:
+ *  - https://tools.ietf.org/html/rfc8259
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+/* Arrays and objects nested deeper than this are rejected, rather than overflowing the stack */
+pub const MAX_DEPTH: usize = 128;
+
+/* The byte offset the parser gave up at */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub offset: usize,
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref members) => members.iter().find(|member| member.0 == key).map(|member| &member.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match *self {
+            Value::Array(ref values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+
+    /* Only whole numbers that fit */
+    pub fn as_u64(&self) -> Option<u64> {
+        self.as_f64()
+            .filter(|&number| number >= 0.0 && number <= u64::MAX as f64 && number.fract() == 0.0)
+            .map(|number| number as u64)
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, Error> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+        depth: 0,
+    };
+
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.text.len() {
+        return Err(parser.error());
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self) -> Error {
+        Error {
+            offset: self.pos,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Result<u8, Error> {
+        let byte = self.peek().ok_or_else(|| self.error())?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), Error> {
+        if self.peek() != Some(byte) {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, Error> {
+        if !self.text[self.pos..].starts_with(word.as_bytes()) {
+            return Err(self.error());
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.nested(Parser::object),
+            Some(b'[') => self.nested(Parser::array),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b'-') | Some(b'0' ..= b'9') => self.number(),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Parser<'a>) -> Result<Value, Error>) -> Result<Value, Error> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error());
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn object(&mut self) -> Result<Value, Error> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            let value = self.value()?;
+            members.push((key, value));
+
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => {},
+                b'}' => return Ok(Value::Object(members)),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error());
+                },
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, Error> {
+        self.expect(b'[')?;
+        let mut values = Vec::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(values));
+        }
+
+        loop {
+            values.push(self.value()?);
+
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => {},
+                b']' => return Ok(Value::Array(values)),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error());
+                },
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.expect(b'"')?;
+        let mut string = String::new();
+
+        loop {
+            /* Copy everything up to the next quote or escape in one go */
+            let start = self.pos;
+            while let Some(byte) = self.peek() {
+                if byte == b'"' || byte == b'\\' || byte < 0x20 {
+                    break;
+                }
+                self.pos += 1;
+            }
+            let run = str::from_utf8(&self.text[start..self.pos]).map_err(|_| Error { offset: start })?;
+            string.push_str(run);
+
+            match self.next()? {
+                b'"' => return Ok(string),
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => {
+                            self.pos -= 1;
+                            return Err(self.error());
+                        },
+                    };
+                    string.push(c);
+                },
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error());
+                },
+            }
+        }
+    }
+
+    /* \uXXXX, characters outside the BMP come as a surrogate pair */
+    fn unicode_escape(&mut self) -> Result<char, Error> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            self.expect(b'\\')?;
+            self.expect(b'u')?;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(self.error());
+            }
+            0x10000 + ((high - 0xd800) << 10 | (low - 0xdc00))
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error())
+    }
+
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let digits = self.text.get(self.pos..self.pos + 4).ok_or_else(|| self.error())?;
+        let digits = str::from_utf8(digits).map_err(|_| self.error())?;
+        let val = u32::from_str_radix(digits, 16).map_err(|_| self.error())?;
+        self.pos += 4;
+        Ok(val)
+    }
+
+    fn number(&mut self) -> Result<Value, Error> {
+        let start = self.pos;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E') | Some(b'0' ..= b'9') = self.peek() {
+            self.pos += 1;
+        }
+
+        str::from_utf8(&self.text[start..self.pos]).ok()
+            .and_then(|number| number.parse().ok())
+            .map(Value::Number)
+            .ok_or(Error { offset: start })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        let value = parse(r#" {"a": [1, -2.5e1, true, false, null], "b": {}} "#).unwrap();
+        assert_eq!(value, Value::Object(vec![
+            (String::from("a"), Value::Array(vec![
+                Value::Number(1.0),
+                Value::Number(-25.0),
+                Value::Bool(true),
+                Value::Bool(false),
+                Value::Null,
+            ])),
+            (String::from("b"), Value::Object(Vec::new())),
+        ]));
+        assert_eq!(value.get("a").and_then(Value::as_array).map(|a| a[0].as_u64()), Some(Some(1)));
+        assert_eq!(value.get("a").and_then(Value::as_array).map(|a| a[1].as_u64()), Some(None));
+    }
+
+    #[test]
+    fn escapes() {
+        let value = parse(r#""\"\\\/\b\f\n\r\t\u00e9\u20AC""#).unwrap();
+        assert_eq!(value.as_str(), Some("\"\\/\u{8}\u{c}\n\r\t\u{e9}\u{20ac}"));
+        assert_eq!(parse("\"caf\u{e9}\"").unwrap().as_str(), Some("caf\u{e9}"));
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        assert_eq!(parse(r#""\ud83d\ude00""#).unwrap().as_str(), Some("\u{1f600}"));
+        /* A high surrogate has to be followed by a low one */
+        assert_eq!(parse(r#""\ud83d""#), Err(Error { offset: 7 }));
+        assert_eq!(parse(r#""\ud83d\u0041""#), Err(Error { offset: 13 }));
+        /* And a low one on its own isn't a character */
+        assert_eq!(parse(r#""\ude00""#), Err(Error { offset: 7 }));
+    }
+
+    #[test]
+    fn error_offsets() {
+        assert_eq!(parse(""), Err(Error { offset: 0 }));
+        assert_eq!(parse("[1, 2"), Err(Error { offset: 5 }));
+        assert_eq!(parse("[1 2]"), Err(Error { offset: 3 }));
+        assert_eq!(parse(r#"{"a" 1}"#), Err(Error { offset: 5 }));
+        assert_eq!(parse(r#""\x""#), Err(Error { offset: 2 }));
+        assert_eq!(parse("\"a\nb\""), Err(Error { offset: 2 }));
+        assert_eq!(parse("[1, 2] 3"), Err(Error { offset: 7 }));
+        assert_eq!(parse("[1, -]"), Err(Error { offset: 4 }));
+        assert_eq!(parse("[tru]"), Err(Error { offset: 1 }));
+    }
+
+    #[test]
+    fn depth_limit() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(parse(&nested(MAX_DEPTH + 1)), Err(Error { offset: MAX_DEPTH }));
+        assert_eq!(parse(&"[".repeat(1_000_000)), Err(Error { offset: MAX_DEPTH }));
+    }
+}
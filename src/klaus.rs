@@ -0,0 +1,117 @@
+use cpu::{Cpu, Nmos6502, Ricoh2A03, Variant};
+use mem::{FlatMem, Mem};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/*
+ * Runs Klaus Dormann's 6502 functional and decimal tests. They're flat
+ * binaries for a bare 6502 with RAM everywhere, and report by looping
+ * forever on a jump or branch to itself: at the success address if
+ * everything passed, anywhere else if a test failed.
+ *
+ * Both presets run on the Nmos6502 variant, since the default builds test
+ * decimal mode. The 2A03 has none, so running the functional test on it
+ * needs a build with disable_decimal = 1, which moves the success trap:
+ * take its address from that build's listing. The decimal test also
+ * leaves 0 at ERROR when it passes, and must be built to end with a JMP *
+ * (or a similar trap) rather than BRK.
+ *
+ * References:
+ *  - https://github.com/Klaus2m5/6502_65C02_functional_tests
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Test {
+    /* Where the binary is loaded and where it starts running */
+    pub origin: u16,
+    pub start: u16,
+    /* The trap reached when everything passed, None if any trap will do */
+    pub success: Option<u16>,
+    /* A byte that's left 0 when everything passed */
+    pub error: Option<u16>,
+    /* Run on an Nmos6502 rather than a Ricoh2A03 */
+    pub decimal_mode: bool,
+}
+
+/* As assembled from the repo's default configuration, decimal mode included */
+pub const FUNCTIONAL: Test = Test {
+    origin: 0x0000,
+    start: 0x0400,
+    success: Some(0x3469),
+    error: None,
+    decimal_mode: true,
+};
+
+pub const DECIMAL: Test = Test {
+    origin: 0x0200,
+    start: 0x0200,
+    success: None,
+    error: Some(0x000b),
+    decimal_mode: true,
+};
+
+/* The functional test takes a little under 100 million cycles */
+pub const MAX_CYCLES: usize = 200_000_000;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /* Never got stuck within this many cycles */
+    Timeout(usize),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TestResult {
+    /* Where it got stuck */
+    pub trap: u16,
+    pub error: Option<u8>,
+    pub cycles: usize,
+    pub passed: bool,
+}
+
+pub fn load<V: Variant>(binary: &[u8], test: &Test, variant: V) -> Cpu<FlatMem, V> {
+    let mut mem = FlatMem::new();
+    mem.load(test.origin, binary);
+
+    let mut cpu = Cpu::with_variant(mem, variant);
+    cpu.set_pc(test.start);
+    cpu
+}
+
+/* Runs until the program traps, for at most `max_cycles` cycles */
+pub fn run<V: Variant>(cpu: &mut Cpu<FlatMem, V>, test: &Test, max_cycles: usize) -> Result<TestResult, Error> {
+    while cpu.clock() < max_cycles {
+        let pc = cpu.pc();
+        cpu.step();
+        if cpu.pc() != pc {
+            continue;
+        }
+
+        let error = test.error.map(|addr| cpu.mem().peek8(addr));
+        return Ok(TestResult {
+            trap: pc,
+            error,
+            cycles: cpu.clock(),
+            passed: test.success.is_none_or(|success| success == pc) && error.is_none_or(|error| error == 0),
+        });
+    }
+
+    Err(Error::Timeout(max_cycles))
+}
+
+pub fn run_file<P: AsRef<Path>>(path: P, test: &Test, max_cycles: usize) -> Result<TestResult, Error> {
+    let binary = fs::read(path)?;
+    if test.decimal_mode {
+        run(&mut load(&binary, test, Nmos6502), test, max_cycles)
+    } else {
+        run(&mut load(&binary, test, Ricoh2A03), test, max_cycles)
+    }
+}
@@ -0,0 +1,38 @@
+mod apu;
+mod crc;
+mod gamedb;
+mod image;
+mod json;
+mod movie;
+mod opcode;
+mod ppu;
+mod ram;
+mod record;
+
+pub mod cartridge;
+pub mod controller;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod mapper;
+pub mod mem;
+pub mod nes;
+pub mod ntsc;
+pub mod palette;
+pub mod region;
+
+/* Test ROM and CPU test suite runners, for the binary and integration tests */
+pub mod blargg;
+pub mod harte;
+pub mod klaus;
+
+pub use cartridge::Cartridge;
+pub use cpu::Cpu;
+pub use gamedb::GameDb;
+pub use image::ImageFormat;
+pub use mem::{FlatMem, Mem, MemMap};
+pub use movie::Movie;
+pub use nes::Nes;
+pub use ram::RamInit;
+pub use record::{Recorder, RecordFormat};
+pub use region::Region;
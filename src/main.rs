@@ -1,19 +1,419 @@
-mod cartridge;
-mod mem;
-mod cpu;
-mod opcode;
+extern crate rusty_nest;
 
-use cartridge::Cartridge;
-use mem::MemMap;
-use cpu::Cpu;
+use rusty_nest::{GameDb, ImageFormat, Movie, Nes, RamInit, Recorder, RecordFormat, Region};
+use rusty_nest::blargg::{self, TestResult};
+use rusty_nest::cartridge::Rom;
+use rusty_nest::harte;
+use rusty_nest::klaus::{self, Test};
+use rusty_nest::mapper::Registry;
+use rusty_nest::palette::Palette;
 
-use std::fs::File;
+use std::env;
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* Exit codes, so scripts can tell a failed run from a bad command line */
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+/* A minute of emulated time */
+const BLARGG_FRAMES: usize = 60 * 60;
+
+const USAGE: &str = "\
+Usage: rusty-nest <command> [options]
+
+Commands:
+  run ROM --frames N        Run at the console's real speed
+  debug ROM                 Run in the interactive debugger
+  info ROM                  Print the ROM header
+  test ROM --log FILE       Compare a CPU trace against a log
+  headless ROM --frames N   Run without pacing, for screenshots and recordings
+  blargg ROM|DIR            Run test ROMs that report results at $6000
+  harte FILE|DIR            Run single step CPU tests from JSON files
+  klaus BIN                 Run a Klaus Dormann 6502 test binary
+  mappers                   List the supported mappers
+
+Options:
+  --region ntsc|pal|dendy   Override the region from the header
+  --gamedb FILE             Look up regions the header doesn't give
+  --palette FILE            Use a 64 or 512 entry .pal file
+  --movie FILE              Play back an FM2 input movie
+  --ram-init POLICY         zeros, ones, alternating, random or random:SEED
+  --frames N                Stop after N frames (for blargg, give up after N)
+  --out FILE                Save the last frame as PNG or PPM (headless)
+  --screenshot-at FRAME     Same as --frames FRAME, needs --out (headless)
+  --record FILE             Record to AVI or Y4M+WAV (headless)
+  --record-every N          Only record every Nth frame (headless)
+  --log FILE                Trace log to compare against (test)
+  --align-ppu               Move the PPU to where the log starts (test)
+  --pc ADDR                 Start at ADDR, in hex (test, debug, klaus)
+  --suite NAME              functional or decimal (klaus)
+  --origin ADDR             Load the binary at ADDR, in hex (klaus)
+  --success ADDR            The trap address that means a pass (klaus)
+  --cpu 2a03|6502           Run without or with decimal mode (klaus)
+
+Exits with 0 on success, 1 on failure and 2 on a bad command line.";
+
+#[derive(Default)]
+struct Options {
+    region: Option<Region>,
+    gamedb: Option<String>,
+    palette: Option<String>,
+    movie: Option<String>,
+    ram_init: RamInit,
+    frames: Option<usize>,
+    out: Option<String>,
+    screenshot_at: bool,
+    record: Option<String>,
+    record_every: usize,
+    log: Option<String>,
+    align_ppu: bool,
+    pc: Option<u16>,
+    suite: Option<Test>,
+    origin: Option<u16>,
+    success: Option<u16>,
+    decimal_mode: Option<bool>,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+fn fail<E: Debug>(what: &str, err: E) -> ! {
+    eprintln!("{}: {:?}", what, err);
+    process::exit(EXIT_FAILURE);
+}
+
+fn parse_options<I: Iterator<Item = String>>(mut args: I) -> Options {
+    let mut options = Options {
+        record_every: 1,
+        ..Options::default()
+    };
+
+    while let Some(arg) = args.next() {
+        if arg == "--align-ppu" {
+            options.align_ppu = true;
+            continue;
+        }
+
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--region" => options.region = Some(Region::from_name(&value).unwrap_or_else(|| usage())),
+            "--gamedb" => options.gamedb = Some(value),
+            "--palette" => options.palette = Some(value),
+            "--movie" => options.movie = Some(value),
+            "--ram-init" => options.ram_init = RamInit::from_name(&value).unwrap_or_else(|| usage()),
+            "--frames" => options.frames = Some(value.parse().unwrap_or_else(|_| usage())),
+            "--out" => options.out = Some(value),
+            "--screenshot-at" => {
+                options.frames = Some(value.parse().unwrap_or_else(|_| usage()));
+                options.screenshot_at = true;
+            },
+            "--record" => options.record = Some(value),
+            "--record-every" => options.record_every = value.parse().unwrap_or_else(|_| usage()),
+            "--log" => options.log = Some(value),
+            "--pc" => options.pc = Some(parse_addr(&value)),
+            "--suite" => options.suite = Some(match value.as_str() {
+                "functional" => klaus::FUNCTIONAL,
+                "decimal" => klaus::DECIMAL,
+                _ => usage(),
+            }),
+            "--origin" => options.origin = Some(parse_addr(&value)),
+            "--success" => options.success = Some(parse_addr(&value)),
+            "--cpu" => options.decimal_mode = Some(match value.as_str() {
+                "2a03" => false,
+                "6502" => true,
+                _ => usage(),
+            }),
+            _ => usage(),
+        }
+    }
+    if options.screenshot_at && options.out.is_none() {
+        usage();
+    }
+    options
+}
+
+fn parse_addr(value: &str) -> u16 {
+    u16::from_str_radix(value.trim_start_matches('$'), 16).unwrap_or_else(|_| usage())
+}
+
+fn load(rom: &str, options: &Options) -> Nes {
+    let db = match options.gamedb {
+        Some(ref path) => GameDb::load(path).unwrap_or_else(|err| fail(&format!("Failed to load {}", path), err)),
+        None => GameDb::new(),
+    };
+    let mut nes = Nes::load_rom_with(rom, options.region, &db)
+        .unwrap_or_else(|err| fail(&format!("Failed to load {}", rom), err));
+
+    if let Some(ref path) = options.palette {
+        let palette = Palette::load(path).unwrap_or_else(|err| fail(&format!("Failed to load {}", path), err));
+        nes.set_palette(palette);
+    }
+
+    nes.set_ram_init(options.ram_init);
+    nes.power_on();
+    nes
+}
+
+fn load_movie(options: &Options) -> Option<Movie> {
+    options.movie.as_ref().map(|path| {
+        Movie::load(path).unwrap_or_else(|err| fail(&format!("Failed to load {}", path), err))
+    })
+}
+
+fn run_frame(nes: &mut Nes, movie: &Option<Movie>) {
+    let frame = nes.frame();
+    if let Some(input) = movie.as_ref().and_then(|movie| movie.frame(frame)) {
+        input.apply(nes);
+    }
+    nes.run_frame();
+}
+
+/*
+ * There's no display yet, so this only keeps the emulation at real speed,
+ * and without a window to close it has to be told when to stop
+ */
+fn run(rom: &str, options: Options) {
+    let limit = options.frames.unwrap_or_else(|| usage());
+    let mut nes = load(rom, &options);
+    let movie = load_movie(&options);
+
+    let (numerator, denominator) = nes.region().frame_rate();
+    let frame_time = Duration::from_nanos(1_000_000_000 * denominator as u64 / numerator as u64);
+
+    let start = Instant::now();
+    let mut frames = 0;
+    while frames < limit {
+        run_frame(&mut nes, &movie);
+        frames += 1;
+
+        let target = frame_time * frames as u32;
+        let elapsed = start.elapsed();
+        if target > elapsed {
+            thread::sleep(target - elapsed);
+        }
+    }
+}
+
+fn debug(rom: &str, options: Options) {
+    let mut nes = load(rom, &options);
+    if let Some(pc) = options.pc {
+        nes.cpu_mut().set_pc(pc);
+    }
+    nes.cpu_mut().interactive();
+}
+
+fn info(path: &str) {
+    let mut file = File::open(path).unwrap_or_else(|err| fail(&format!("Failed to open {}", path), err));
+    let rom = Rom::new(&mut file).unwrap_or_else(|err| fail(&format!("Failed to read {}", path), err));
+    let header = &rom.header;
+
+    let registry = Registry::default();
+    let name = registry.find(header.mapper, header.submapper).map_or("unsupported", |entry| entry.name);
+    let region = header.region.map_or(String::from("unknown"), |region| format!("{:?}", region));
+
+    println!("Format:    {}", if header.nes2 { "NES 2.0" } else { "iNES" });
+    println!("Mapper:    {}.{} ({})", header.mapper, header.submapper, name);
+    println!("PRG ROM:   {}K", rom.prg_rom.len() / 1024);
+    println!("CHR ROM:   {}K", rom.chr_rom.len() / 1024);
+    println!("Mirroring: {:?}", header.mirroring);
+    println!("Battery:   {}", if header.battery { "yes" } else { "no" });
+    println!("Region:    {}", region);
+    println!("CRC32:     {:08x}", rom.crc32());
+}
+
+fn test(rom: &str, options: Options) {
+    let log = options.log.clone().unwrap_or_else(|| usage());
+    let mut nes = load(rom, &options);
+    if let Some(pc) = options.pc {
+        nes.cpu_mut().set_pc(pc);
+    }
+
+    let text = fs::read_to_string(&log).unwrap_or_else(|err| fail(&format!("Failed to read {}", log), err));
+    if options.align_ppu {
+        match text.lines().next().and_then(|line| nes.cpu_mut().align_to_log(line)) {
+            Some(offset) => println!("Moved the PPU {} dots to line up with the log", offset),
+            None => println!("The log has no PPU position to line up with"),
+        }
+    }
+
+    match nes.cpu_mut().compare_log(text.as_bytes(), false) {
+        Ok(None) => println!("Test Passed"),
+        Ok(Some(mismatch)) => {
+            println!("Test Failed (line {})", mismatch.line);
+            println!("Expected: {}", mismatch.expected);
+            println!("Obtained: {}", mismatch.obtained);
+            process::exit(EXIT_FAILURE);
+        },
+        Err(err) => fail(&format!("Failed to read {}", log), err),
+    }
+}
+
+/* Runs without a display, saving a screenshot of the last frame and/or a recording */
+fn headless(rom: &str, options: Options) {
+    let frames = options.frames.unwrap_or_else(|| usage());
+    let mut nes = load(rom, &options);
+    let movie = load_movie(&options);
+
+    if let Some(ref path) = options.record {
+        let sample_rate = nes.cpu().mem().apu().sample_rate();
+        let format = RecordFormat::from_path(path);
+        match Recorder::create(path, format, nes.region(), sample_rate, options.record_every) {
+            Ok(recorder) => nes.start_recording(recorder),
+            Err(err) => fail(&format!("Failed to create {}", path), err),
+        }
+    }
+
+    while nes.frame() < frames {
+        run_frame(&mut nes, &movie);
+    }
+
+    if let Err(err) = nes.stop_recording() {
+        fail(&format!("Failed to record {}", options.record.unwrap()), err);
+    }
+
+    if let Some(path) = options.out {
+        let image = nes.screenshot(ImageFormat::from_path(&path));
+        if let Err(err) = fs::write(&path, image) {
+            fail(&format!("Failed to write {}", path), err);
+        }
+    }
+}
+
+/* Exits with EXIT_FAILURE unless every ROM passes */
+fn blargg(path: &str, options: Options) {
+    let frames = options.frames.unwrap_or(BLARGG_FRAMES);
+    let results = if Path::new(path).is_dir() {
+        blargg::run_dir(path, frames).unwrap_or_else(|err| fail(&format!("Failed to read {}", path), err))
+    } else {
+        vec![(PathBuf::from(path), blargg::run_rom(path, frames))]
+    };
+
+    let mut passed = 0;
+    for (rom, result) in &results {
+        let name = rom.file_name().map_or(rom.as_os_str(), |name| name).to_string_lossy();
+        match *result {
+            Ok(TestResult { status: 0, .. }) => {
+                println!("PASS  {}", name);
+                passed += 1;
+            },
+            Ok(ref result) => println!("FAIL  {} (status {}): {}", name, result.status, result.text),
+            Err(ref err) => println!("ERROR {}: {:?}", name, err),
+        }
+    }
+
+    println!("{}/{} passed", passed, results.len());
+    if passed != results.len() {
+        process::exit(EXIT_FAILURE);
+    }
+}
+
+/* Exits with EXIT_FAILURE unless every case in every file passes */
+fn harte(path: &str) {
+    let results = if Path::new(path).is_dir() {
+        harte::run_dir(path).unwrap_or_else(|err| fail(&format!("Failed to read {}", path), err))
+    } else {
+        vec![(PathBuf::from(path), harte::run_file(path))]
+    };
+
+    let mut passed = 0;
+    for (file, result) in &results {
+        let name = file.file_name().map_or(file.as_os_str(), |name| name).to_string_lossy();
+        match *result {
+            Ok(ref report) if report.failures.is_empty() => {
+                println!("PASS  {} ({} cases)", name, report.total);
+                passed += 1;
+            },
+            Ok(ref report) => {
+                let first = &report.failures[0];
+                println!("FAIL  {} ({}/{} cases passed), \"{}\": {}",
+                         name, report.passed(), report.total, first.name, first.message);
+            },
+            Err(ref err) => println!("ERROR {}: {:?}", name, err),
+        }
+    }
+
+    println!("{}/{} passed", passed, results.len());
+    if passed != results.len() {
+        process::exit(EXIT_FAILURE);
+    }
+}
+
+fn klaus(path: &str, options: Options) {
+    let mut test = options.suite.unwrap_or(klaus::FUNCTIONAL);
+    if let Some(origin) = options.origin {
+        test.origin = origin;
+    }
+    if let Some(pc) = options.pc {
+        test.start = pc;
+    }
+    if options.success.is_some() {
+        test.success = options.success;
+    }
+    if let Some(decimal_mode) = options.decimal_mode {
+        test.decimal_mode = decimal_mode;
+    }
+
+    match klaus::run_file(path, &test, klaus::MAX_CYCLES) {
+        Ok(result) => {
+            let status = if result.passed { "PASS" } else { "FAIL" };
+            match result.error {
+                Some(error) => println!("{}  trapped at ${:04X} after {} cycles, error {}",
+                                        status, result.trap, result.cycles, error),
+                None => println!("{}  trapped at ${:04X} after {} cycles", status, result.trap, result.cycles),
+            }
+            if !result.passed {
+                process::exit(EXIT_FAILURE);
+            }
+        },
+        Err(err) => fail(&format!("Failed to run {}", path), err),
+    }
+}
+
+fn list_mappers() {
+    for entry in Registry::default().entries() {
+        match entry.submapper {
+            Some(submapper) => println!("{:3}.{:<2} {}", entry.mapper, submapper, entry.name),
+            None => println!("{:3}    {}", entry.mapper, entry.name),
+        }
+    }
+}
 
 fn main() {
-    let mut file = File::open("rom/nestest.nes").unwrap();
-    let cartridge = Cartridge::new(&mut file).unwrap();
+    let mut args = env::args().skip(1);
+    let command = args.next().unwrap_or_else(|| usage());
+    match command.as_str() {
+        "mappers" | "--list-mappers" => return list_mappers(),
+        "help" | "--help" | "-h" => return println!("{}", USAGE),
+        _ => {},
+    }
+
+    /* The old `rusty-nest ROM --screenshot-at FRAME --out FILE` is a headless run */
+    let (command, rom) = match command.as_str() {
+        "run" | "debug" | "info" | "test" | "headless" | "blargg" | "harte" | "klaus" => {
+            let rom = args.next().unwrap_or_else(|| usage());
+            (command, rom)
+        },
+        _ if Path::new(&command).is_file() => (String::from("headless"), command),
+        _ => usage(),
+    };
 
-    let mem = MemMap::new(cartridge.as_ref());
-    let mut cpu = Cpu::new(mem);
-    cpu.interactive();
+    let options = parse_options(args);
+    match command.as_str() {
+        "run" => run(&rom, options),
+        "debug" => debug(&rom, options),
+        "info" => info(&rom),
+        "test" => test(&rom, options),
+        "headless" => headless(&rom, options),
+        "blargg" => blargg(&rom, options),
+        "harte" => harte(&rom),
+        "klaus" => klaus(&rom, options),
+        _ => usage(),
+    }
 }
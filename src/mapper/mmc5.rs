@@ -0,0 +1,912 @@
+use apu::{DUTY_TABLE, LENGTH_TABLE};
+use cartridge::{Mapper, Mirroring, Rom};
+use mapper::Registry;
+use mem::Mem;
+use ram::{self, RamInit};
+use region::Region;
+
+use std::cell::Cell;
+use std::mem;
+
+/*
+ * References:
+ *  - http://wiki.nesdev.com/w/index.php/MMC5
+ *  - http://wiki.nesdev.com/w/index.php/MMC5_audio
+ *
+ * The MMC5 has no idea what the PPU is doing, it works it out by
+ * watching the PPU bus:
+ *  - Three reads in a row from the same nametable address happen at the
+ *    end of every rendered scanline (dots 337, 339 and 1 of the next line)
+ *  - After that, fetches come in a fixed order: 32 background tiles
+ *    (nametable, attribute, pattern low, pattern high), 8 sprites
+ *    (two garbage nametable reads and two pattern reads), then the first
+ *    two background tiles of the next line
+ *  - If the PPU stops reading for a few CPU cycles, it isn't rendering
+ *
+ * PPU reads go through &self, so that state lives in a Cell.
+ */
+
+const PRG_RAM_SIZE: usize = 0x10000;
+const CHR_RAM_SIZE: usize = 0x2000;
+const EXRAM_SIZE: usize = 0x0400;
+
+const BG_FETCHES: usize = 32 * 4;
+const SPRITE_FETCHES: usize = 8 * 4;
+const PREFETCHES: usize = 2 * 4;
+
+/* CPU cycles between envelope/length clocks (~240Hz), like the APU's frame counter */
+const FRAME_PERIOD: u16 = 7457;
+const FRAME_PERIOD_PAL: u16 = 8313;
+
+#[derive(Copy, Clone, Default)]
+struct PpuState {
+    in_frame: bool,
+    scanline: u8,
+    irq_pending: bool,
+    idle_cycles: u8,
+
+    last_addr: u16,
+    matches: u8,
+    fetch: usize,
+
+    /* Latched by the nametable fetch of the current background tile */
+    ex_attr: u8,
+    in_split: bool,
+    split_tile: u8,
+    split_y: u8,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Fetch {
+    Idle,
+    Background(usize),
+    Sprite,
+}
+
+#[derive(Default)]
+struct Pulse {
+    enabled: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: u8,
+    halt: bool,
+    constant_volume: bool,
+    volume: u8,
+    envelope_start: bool,
+    envelope_divider: u8,
+    envelope_decay: u8,
+}
+
+impl Pulse {
+    fn write8(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.duty = val >> 6;
+                self.halt = val & 0x20 != 0;
+                self.constant_volume = val & 0x10 != 0;
+                self.volume = val & 0x0f;
+            },
+            2 => self.period = (self.period & 0x0700) | val as u16,
+            3 => {
+                self.period = (self.period & 0x00ff) | ((val as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.step = 0;
+                self.envelope_start = true;
+            },
+            _ => {},
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_frame(&mut self) {
+        if self.envelope_start {
+            self.envelope_start = false;
+            self.envelope_decay = 15;
+            self.envelope_divider = self.volume;
+        } else if self.envelope_divider == 0 {
+            self.envelope_divider = self.volume;
+            if self.envelope_decay > 0 {
+                self.envelope_decay -= 1;
+            } else if self.halt {
+                self.envelope_decay = 15;
+            }
+        } else {
+            self.envelope_divider -= 1;
+        }
+
+        if !self.halt && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else if self.constant_volume {
+            self.volume
+        } else {
+            self.envelope_decay
+        }
+    }
+}
+
+pub struct Mmc5 {
+    prg_rom: Box<[u8]>,
+    prg_ram: Box<[u8]>,
+    chr: Box<[u8]>,
+    chr_is_ram: bool,
+    exram: [u8; EXRAM_SIZE],
+
+    prg_mode: u8,
+    prg_ram_protect: [u8; 2],
+    prg_banks: [u8; 5],
+
+    chr_mode: u8,
+    chr_upper: u16,
+    chr_banks: [u16; 12],
+    chr_last_b: bool,
+
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attr: u8,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+
+    multiplicand: u8,
+    multiplier: u8,
+
+    sprite_8x16: bool,
+    ppu: Cell<PpuState>,
+
+    pulses: [Pulse; 2],
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_irq: bool,
+    pcm: u8,
+    frame_divider: u16,
+    frame_period: u16,
+    apu_cycle: bool,
+}
+
+pub fn register(registry: &mut Registry) {
+    registry.register(5, None, "MMC5", |rom| Ok(Box::new(Mmc5::new(rom))));
+}
+
+impl Mmc5 {
+    pub fn new(rom: Rom) -> Mmc5 {
+        let chr_is_ram = rom.chr_rom.is_empty();
+        let chr = if chr_is_ram {
+            vec![0; CHR_RAM_SIZE].into_boxed_slice()
+        } else {
+            rom.chr_rom
+        };
+
+        Mmc5::with_memory(rom.prg_rom, vec![0; PRG_RAM_SIZE].into_boxed_slice(), chr, chr_is_ram)
+    }
+
+    /* Registers start at their power on values */
+    fn with_memory(prg_rom: Box<[u8]>, prg_ram: Box<[u8]>, chr: Box<[u8]>, chr_is_ram: bool) -> Mmc5 {
+        Mmc5 {
+            prg_rom,
+            prg_ram,
+            chr,
+            chr_is_ram,
+            exram: [0; EXRAM_SIZE],
+
+            prg_mode: 3,
+            prg_ram_protect: [0; 2],
+            prg_banks: [0, 0, 0, 0, 0xff],
+
+            chr_mode: 0,
+            chr_upper: 0,
+            chr_banks: [0; 12],
+            chr_last_b: false,
+
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attr: 0,
+
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+
+            irq_compare: 0,
+            irq_enabled: false,
+
+            multiplicand: 0xff,
+            multiplier: 0xff,
+
+            sprite_8x16: false,
+            ppu: Cell::new(PpuState::default()),
+
+            pulses: [Pulse::default(), Pulse::default()],
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq: false,
+            pcm: 0,
+            frame_divider: 0,
+            frame_period: FRAME_PERIOD,
+            apu_cycle: false,
+        }
+    }
+
+    /* PRG banking */
+    fn prg_offset(&self, addr: u16) -> (bool, usize) {
+        if addr < 0x8000 {
+            let bank = (self.prg_banks[0] & 0x07) as usize;
+            return (false, bank << 13 | (addr as usize & 0x1fff));
+        }
+
+        /* $5117 can only ever map ROM */
+        let last = self.prg_banks[4] | 0x80;
+        let (reg, pages) = match (self.prg_mode, addr) {
+            (0, _) => (last, 4),
+            (1, 0x8000 ..= 0xbfff) | (2, 0x8000 ..= 0xbfff) => (self.prg_banks[2], 2),
+            (1, _) => (last, 2),
+            (2, 0xc000 ..= 0xdfff) => (self.prg_banks[3], 1),
+            (2, _) => (last, 1),
+            _ => match addr {
+                0x8000 ..= 0x9fff => (self.prg_banks[1], 1),
+                0xa000 ..= 0xbfff => (self.prg_banks[2], 1),
+                0xc000 ..= 0xdfff => (self.prg_banks[3], 1),
+                _ => (last, 1),
+            },
+        };
+
+        let page = (addr as usize - 0x8000) >> 13;
+        let bank = (reg as usize & 0x7f & !(pages - 1)) | (page & (pages - 1));
+        let rom = reg & 0x80 != 0;
+        let bank = if rom { bank } else { bank & 0x07 };
+        (rom, bank << 13 | (addr as usize & 0x1fff))
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0x02, 0x01]
+    }
+
+    /* CHR banking */
+    fn chr_offset(&self, addr: u16, set_b: bool) -> usize {
+        let page_size = 0x2000 >> self.chr_mode;
+        let addr = addr as usize & 0x1fff;
+
+        let reg = if !set_b {
+            (addr / page_size + 1) * (page_size / 0x0400) - 1
+        } else if page_size == 0x2000 {
+            11
+        } else {
+            8 + ((addr & 0x0fff) / page_size + 1) * (page_size / 0x0400) - 1
+        };
+
+        let bank = self.chr_banks[reg] as usize;
+        (bank * page_size + (addr & (page_size - 1))) % self.chr.len()
+    }
+
+    /* Background fetches only use the B set with 8x16 sprites */
+    fn chr_uses_b(&self, fetch: Fetch) -> bool {
+        match fetch {
+            _ if !self.sprite_8x16 => false,
+            Fetch::Background(_) => true,
+            Fetch::Sprite => false,
+            Fetch::Idle => self.chr_last_b,
+        }
+    }
+
+    /* PPU bus snooping */
+    fn ppu_fetch(&self, addr: u16) -> (PpuState, Fetch) {
+        let mut state = self.ppu.get();
+        state.idle_cycles = 0;
+
+        let is_nametable = (0x2000..0x3000).contains(&addr);
+        if is_nametable && addr == state.last_addr {
+            state.matches += 1;
+            if state.matches == 2 {
+                self.scanline_start(&mut state);
+            }
+        } else {
+            state.matches = 0;
+        }
+        state.last_addr = addr;
+
+        let fetch = if !state.in_frame {
+            Fetch::Idle
+        } else {
+            let fetch = state.fetch;
+            state.fetch += 1;
+
+            if fetch < BG_FETCHES {
+                Fetch::Background(fetch / 4 + 2)
+            } else if fetch < BG_FETCHES + SPRITE_FETCHES {
+                Fetch::Sprite
+            } else if fetch < BG_FETCHES + SPRITE_FETCHES + PREFETCHES {
+                Fetch::Background((fetch - BG_FETCHES - SPRITE_FETCHES) / 4)
+            } else {
+                Fetch::Idle
+            }
+        };
+
+        (state, fetch)
+    }
+
+    fn scanline_start(&self, state: &mut PpuState) {
+        if !state.in_frame {
+            state.in_frame = true;
+            state.scanline = 0;
+            state.split_y = self.split_scroll % 240;
+        } else {
+            state.scanline = state.scanline.wrapping_add(1);
+            state.split_y = (state.split_y + 1) % 240;
+            if state.scanline == self.irq_compare {
+                state.irq_pending = true;
+            }
+        }
+        state.fetch = 0;
+    }
+
+    fn leave_frame(&self) {
+        let mut state = self.ppu.get();
+        state.in_frame = false;
+        state.irq_pending = false;
+        state.matches = 0;
+        self.ppu.set(state);
+    }
+
+    fn in_frame(&self) -> bool {
+        self.ppu.get().in_frame
+    }
+
+    /* Vertical split */
+    fn split_enabled(&self) -> bool {
+        self.split_control & 0x80 != 0 && self.exram_mode <= 1
+    }
+
+    fn in_split(&self, column: usize) -> bool {
+        let count = (self.split_control & 0x1f) as usize;
+        let column = column & 0x1f;
+        if self.split_control & 0x40 == 0 {
+            column < count
+        } else {
+            column >= count
+        }
+    }
+
+    /* Prefetched tiles belong to the next line */
+    fn split_y(&self, state: &PpuState, column: usize) -> u8 {
+        if column < 2 {
+            (state.split_y + 1) % 240
+        } else {
+            state.split_y
+        }
+    }
+
+    fn nametable_page(&self, vram: &[u8], addr: u16) -> u8 {
+        let page = (self.nametable_mapping >> (((addr >> 10) & 3) * 2)) & 3;
+        let offset = addr as usize & 0x03ff;
+
+        match page {
+            0 | 1 => vram[(page as usize) << 10 | offset],
+            2 if self.exram_mode <= 1 => self.exram[offset],
+            2 => 0,
+            _ if offset >= 0x03c0 => self.fill_attr * 0x55,
+            _ => self.fill_tile,
+        }
+    }
+
+    /* Audio */
+    fn clock_audio(&mut self) {
+        self.apu_cycle = !self.apu_cycle;
+        if self.apu_cycle {
+            for pulse in &mut self.pulses {
+                pulse.clock_timer();
+            }
+        }
+
+        self.frame_divider += 1;
+        if self.frame_divider == self.frame_period {
+            self.frame_divider = 0;
+            for pulse in &mut self.pulses {
+                pulse.clock_frame();
+            }
+        }
+    }
+
+    fn pcm_write(&mut self, val: u8) {
+        if val == 0 {
+            self.pcm_irq = true;
+        } else {
+            self.pcm = val;
+        }
+    }
+}
+
+impl Mem for Mmc5 {
+    fn read8(&mut self, addr: u16) -> u8 {
+        let val = self.peek8(addr);
+        match addr {
+            0x5010 => self.pcm_irq = false,
+            0x5204 => {
+                let mut state = self.ppu.get();
+                state.irq_pending = false;
+                self.ppu.set(state);
+            },
+            0x6000 ..= 0xffff => {
+                /* The NMI vector fetch marks the end of the frame */
+                if addr == 0xfffa || addr == 0xfffb {
+                    self.leave_frame();
+                }
+
+                if self.pcm_read_mode && (0x8000..0xc000).contains(&addr) {
+                    self.pcm_write(val);
+                }
+            },
+            _ => {},
+        }
+        val
+    }
+
+    fn peek8(&self, addr: u16) -> u8 {
+        match addr {
+            0x5010 => {
+                let irq = self.pcm_irq && self.pcm_irq_enabled;
+                (irq as u8) << 7 | self.pcm_read_mode as u8
+            },
+            0x5015 =>
+                (self.pulses[0].length > 0) as u8 |
+                ((self.pulses[1].length > 0) as u8) << 1,
+            0x5204 => {
+                let state = self.ppu.get();
+                (state.irq_pending as u8) << 7 | (state.in_frame as u8) << 6
+            },
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5c00 ..= 0x5fff if self.exram_mode >= 2 => self.exram[addr as usize & 0x03ff],
+            0x6000 ..= 0xffff => {
+                let (rom, offset) = self.prg_offset(addr);
+                if rom {
+                    self.prg_rom[offset % self.prg_rom.len()]
+                } else {
+                    self.prg_ram[offset]
+                }
+            },
+            _ => 0,
+        }
+    }
+
+    fn write8(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x5000 ..= 0x5007 => self.pulses[(addr as usize >> 2) & 1].write8(addr & 3, val),
+            0x5010 => {
+                self.pcm_read_mode = val & 0x01 != 0;
+                self.pcm_irq_enabled = val & 0x80 != 0;
+            },
+            0x5011 if !self.pcm_read_mode => self.pcm_write(val),
+            0x5015 => {
+                self.pulses[0].set_enabled(val & 0x01 != 0);
+                self.pulses[1].set_enabled(val & 0x02 != 0);
+            },
+            0x5100 => self.prg_mode = val & 0x03,
+            0x5101 => self.chr_mode = val & 0x03,
+            0x5102 ..= 0x5103 => self.prg_ram_protect[addr as usize - 0x5102] = val & 0x03,
+            0x5104 => self.exram_mode = val & 0x03,
+            0x5105 => self.nametable_mapping = val,
+            0x5106 => self.fill_tile = val,
+            0x5107 => self.fill_attr = val & 0x03,
+            0x5113 ..= 0x5117 => self.prg_banks[addr as usize - 0x5113] = val,
+            0x5120 ..= 0x512b => {
+                let reg = addr as usize - 0x5120;
+                self.chr_banks[reg] = self.chr_upper << 8 | val as u16;
+                self.chr_last_b = reg >= 8;
+            },
+            0x5130 => self.chr_upper = val as u16 & 0x03,
+            0x5200 => self.split_control = val,
+            0x5201 => self.split_scroll = val,
+            0x5202 => self.split_bank = val,
+            0x5203 => self.irq_compare = val,
+            0x5204 => self.irq_enabled = val & 0x80 != 0,
+            0x5205 => self.multiplicand = val,
+            0x5206 => self.multiplier = val,
+            0x5c00 ..= 0x5fff => {
+                /* Outside of rendering, nametable ExRAM can only be cleared */
+                let val = match self.exram_mode {
+                    0 | 1 if !self.in_frame() => 0,
+                    0 ..= 2 => val,
+                    _ => return,
+                };
+                self.exram[addr as usize & 0x03ff] = val;
+            },
+            0x6000 ..= 0xffff => {
+                let (rom, offset) = self.prg_offset(addr);
+                if !rom && self.prg_ram_writable() {
+                    self.prg_ram[offset] = val;
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn chr_read8(&self, addr: u16) -> u8 {
+        let (state, fetch) = self.ppu_fetch(addr);
+        self.ppu.set(state);
+
+        if let Fetch::Background(column) = fetch {
+            if state.in_split {
+                let fine_y = (self.split_y(&state, column) & 7) as usize;
+                let offset = (self.split_bank as usize) << 12 |
+                    (state.split_tile as usize) << 4 |
+                    (addr as usize & 0x08) | fine_y;
+                return self.chr[offset % self.chr.len()];
+            }
+
+            if self.exram_mode == 1 {
+                let bank = (self.chr_upper as usize) << 6 | (state.ex_attr & 0x3f) as usize;
+                let offset = bank << 12 | (addr as usize & 0x0fff);
+                return self.chr[offset % self.chr.len()];
+            }
+        }
+
+        self.chr[self.chr_offset(addr, self.chr_uses_b(fetch))]
+    }
+
+    fn chr_write8(&mut self, addr: u16, val: u8) {
+        if self.chr_is_ram {
+            let offset = self.chr_offset(addr, self.chr_last_b && self.sprite_8x16);
+            self.chr[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_mapping {
+            0x00 => Mirroring::SingleScreenLower,
+            0x55 => Mirroring::SingleScreenUpper,
+            0x50 => Mirroring::Horizontal,
+            _ => Mirroring::Vertical,
+        }
+    }
+
+    fn power_on(&mut self) {
+        let prg_rom = mem::replace(&mut self.prg_rom, Box::new([]));
+        let prg_ram = mem::replace(&mut self.prg_ram, Box::new([]));
+        let chr = mem::replace(&mut self.chr, Box::new([]));
+        let frame_period = self.frame_period;
+        *self = Mmc5::with_memory(prg_rom, prg_ram, chr, self.chr_is_ram);
+        self.frame_period = frame_period;
+    }
+
+    /* The registers survive, but the CPU stopping looks like the end of the frame */
+    fn reset(&mut self) {
+        self.leave_frame();
+        self.pcm_irq = false;
+    }
+
+    fn init_ram(&mut self, init: RamInit) {
+        init.fill(&mut self.prg_ram, ram::STREAM_WRAM);
+    }
+
+    fn set_region(&mut self, region: Region) {
+        self.frame_period = if region.pal_apu() { FRAME_PERIOD_PAL } else { FRAME_PERIOD };
+    }
+
+    fn drives_cpu_bus(&self, addr: u16) -> bool {
+        match addr {
+            0x5010 | 0x5015 | 0x5204 ..= 0x5206 | 0x6000 ..= 0xffff => true,
+            0x5c00 ..= 0x5fff => self.exram_mode >= 2,
+            _ => false,
+        }
+    }
+
+    fn nametable_read8(&self, vram: &[u8], addr: u16) -> u8 {
+        let (mut state, fetch) = self.ppu_fetch(addr);
+
+        let val = match fetch {
+            /* Nametable byte */
+            Fetch::Background(column) if state.fetch % 4 == 1 => {
+                state.in_split = self.split_enabled() && self.in_split(column);
+                if state.in_split {
+                    let row = (self.split_y(&state, column) >> 3) as usize;
+                    state.split_tile = self.exram[row * 32 + (column & 0x1f)];
+                    state.split_tile
+                } else {
+                    state.ex_attr = self.exram[addr as usize & 0x03ff];
+                    self.nametable_page(vram, addr)
+                }
+            },
+
+            /* Attribute byte */
+            Fetch::Background(column) if state.in_split => {
+                let y = self.split_y(&state, column) as usize;
+                let column = column & 0x1f;
+                let attr = self.exram[0x03c0 + (y >> 5) * 8 + (column >> 2)];
+                let shift = ((y >> 4) & 1) << 2 | ((column >> 1) & 1) << 1;
+                ((attr >> shift) & 0x03) * 0x55
+            },
+            Fetch::Background(_) if self.exram_mode == 1 => (state.ex_attr >> 6) * 0x55,
+
+            _ => self.nametable_page(vram, addr),
+        };
+
+        self.ppu.set(state);
+        val
+    }
+
+    fn nametable_write8(&mut self, vram: &mut [u8], addr: u16, val: u8) {
+        let page = (self.nametable_mapping >> (((addr >> 10) & 3) * 2)) & 3;
+        let offset = addr as usize & 0x03ff;
+
+        match page {
+            0 | 1 => vram[(page as usize) << 10 | offset] = val,
+            2 if self.exram_mode <= 1 => self.exram[offset] = val,
+            _ => {},
+        }
+    }
+
+    fn snoop_write8(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x2000 => self.sprite_8x16 = val & 0x20 != 0,
+            0x2001 if val & 0x18 == 0 => self.leave_frame(),
+            _ => {},
+        }
+    }
+
+    fn clock(&mut self) {
+        let mut state = self.ppu.get();
+        if state.in_frame {
+            state.idle_cycles += 1;
+            if state.idle_cycles >= 3 {
+                self.ppu.set(state);
+                self.leave_frame();
+            } else {
+                self.ppu.set(state);
+            }
+        }
+
+        self.clock_audio();
+    }
+
+    fn irq(&self) -> bool {
+        (self.ppu.get().irq_pending && self.irq_enabled) ||
+            (self.pcm_irq && self.pcm_irq_enabled)
+    }
+
+    fn audio(&self) -> f32 {
+        let pulses = (self.pulses[0].output() + self.pulses[1].output()) as f32;
+        let pulse_out = if pulses == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulses + 100.0)
+        };
+
+        pulse_out + self.pcm as f32 * 0.002
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Every 8K PRG bank and 1K CHR bank is filled with its own number */
+    fn mmc5() -> Mmc5 {
+        let prg_rom: Vec<u8> = (0..16 * 0x2000).map(|i| (i / 0x2000) as u8).collect();
+        let chr: Vec<u8> = (0..64 * 0x0400).map(|i| (i / 0x0400) as u8).collect();
+        Mmc5::with_memory(prg_rom.into_boxed_slice(), vec![0; PRG_RAM_SIZE].into_boxed_slice(),
+                          chr.into_boxed_slice(), false)
+    }
+
+    /* Three reads of the same nametable address start a scanline */
+    fn start_scanline(mmc5: &Mmc5, vram: &[u8]) {
+        for _ in 0..3 {
+            mmc5.nametable_read8(vram, 0x2000);
+        }
+        mmc5.nametable_read8(vram, 0x2001);
+    }
+
+    #[test]
+    fn prg_banking() {
+        let mut mmc5 = mmc5();
+
+        /* Mode 3 powers on with the last bank at $E000 */
+        assert_eq!(mmc5.read8(0xe000), 15);
+
+        mmc5.write8(0x5114, 0x81);
+        mmc5.write8(0x5115, 0x82);
+        mmc5.write8(0x5116, 0x83);
+        mmc5.write8(0x5117, 0x04);
+        assert_eq!(mmc5.read8(0x8000), 1);
+        assert_eq!(mmc5.read8(0xa000), 2);
+        assert_eq!(mmc5.read8(0xc000), 3);
+        /* $5117 is always ROM */
+        assert_eq!(mmc5.read8(0xe000), 4);
+
+        /* One 32K bank, the low bits of $5117 are ignored */
+        mmc5.write8(0x5100, 0);
+        mmc5.write8(0x5117, 0x85);
+        assert_eq!(mmc5.read8(0x8000), 4);
+        assert_eq!(mmc5.read8(0xe000), 7);
+
+        /* Two 16K banks */
+        mmc5.write8(0x5100, 1);
+        mmc5.write8(0x5115, 0x83);
+        assert_eq!(mmc5.read8(0x8000), 2);
+        assert_eq!(mmc5.read8(0xa000), 3);
+        assert_eq!(mmc5.read8(0xc000), 4);
+    }
+
+    #[test]
+    fn prg_ram() {
+        let mut mmc5 = mmc5();
+        mmc5.write8(0x5113, 0x02);
+
+        /* Writes are ignored until both protect registers are set */
+        mmc5.write8(0x6000, 0x42);
+        assert_eq!(mmc5.read8(0x6000), 0x00);
+
+        mmc5.write8(0x5102, 0x02);
+        mmc5.write8(0x5103, 0x01);
+        mmc5.write8(0x6000, 0x42);
+        assert_eq!(mmc5.read8(0x6000), 0x42);
+
+        /* The same RAM bank mapped at $8000 */
+        mmc5.write8(0x5114, 0x02);
+        assert_eq!(mmc5.read8(0x8000), 0x42);
+    }
+
+    #[test]
+    fn chr_banking() {
+        let mut mmc5 = mmc5();
+
+        /* 8K pages use $5127 */
+        mmc5.write8(0x5127, 1);
+        assert_eq!(mmc5.chr_read8(0x0000), 8);
+        assert_eq!(mmc5.chr_read8(0x1c00), 15);
+
+        /* 1K pages, with $5130 supplying the upper bits */
+        mmc5.write8(0x5101, 3);
+        mmc5.write8(0x5120, 5);
+        mmc5.write8(0x5127, 9);
+        assert_eq!(mmc5.chr_read8(0x0000), 5);
+        assert_eq!(mmc5.chr_read8(0x1c00), 9);
+
+        mmc5.write8(0x5130, 1);
+        mmc5.write8(0x5120, 0x02);
+        assert_eq!(mmc5.chr_read8(0x0000), 0x02);
+        assert_eq!(mmc5.chr_banks[0], 0x0102);
+    }
+
+    #[test]
+    fn exram_modes() {
+        let mut mmc5 = mmc5();
+        let vram = [0; 0x0800];
+
+        /* Mode 0 is a nametable, which the CPU can only clear outside a frame */
+        mmc5.write8(0x5105, 0x02);
+        mmc5.exram[0] = 0x33;
+        assert_eq!(mmc5.nametable_read8(&vram, 0x2000), 0x33);
+        mmc5.write8(0x5c00, 0x44);
+        assert_eq!(mmc5.exram[0], 0x00);
+        assert!(!mmc5.drives_cpu_bus(0x5c00));
+
+        /* Mode 2 is plain RAM */
+        mmc5.write8(0x5104, 2);
+        mmc5.write8(0x5c00, 0x55);
+        assert!(mmc5.drives_cpu_bus(0x5c00));
+        assert_eq!(mmc5.read8(0x5c00), 0x55);
+        assert_eq!(mmc5.nametable_read8(&vram, 0x2000), 0x00);
+
+        /* Mode 3 is read only */
+        mmc5.write8(0x5104, 3);
+        mmc5.write8(0x5c00, 0x66);
+        assert_eq!(mmc5.read8(0x5c00), 0x55);
+    }
+
+    #[test]
+    fn fill_mode() {
+        let mut mmc5 = mmc5();
+        let vram = [0; 0x0800];
+
+        mmc5.write8(0x5105, 0xff);
+        mmc5.write8(0x5106, 0x24);
+        mmc5.write8(0x5107, 0x02);
+        assert_eq!(mmc5.nametable_read8(&vram, 0x2000), 0x24);
+        assert_eq!(mmc5.nametable_read8(&vram, 0x23c0), 0xaa);
+    }
+
+    #[test]
+    fn scanline_irq() {
+        let mut mmc5 = mmc5();
+        let vram = [0; 0x0800];
+        mmc5.write8(0x5203, 2);
+        mmc5.write8(0x5204, 0x80);
+
+        start_scanline(&mmc5, &vram);
+        assert_eq!(mmc5.peek8(0x5204), 0x40);
+        start_scanline(&mmc5, &vram);
+        assert!(!mmc5.irq());
+        start_scanline(&mmc5, &vram);
+        assert!(mmc5.irq());
+
+        /* Reading the status acknowledges it */
+        assert_eq!(mmc5.read8(0x5204), 0xc0);
+        assert!(!mmc5.irq());
+
+        /* A few CPU cycles without PPU reads ends the frame */
+        for _ in 0..3 {
+            mmc5.clock();
+        }
+        assert_eq!(mmc5.peek8(0x5204), 0x00);
+    }
+
+    #[test]
+    fn irq_disabled() {
+        let mut mmc5 = mmc5();
+        let vram = [0; 0x0800];
+        mmc5.write8(0x5203, 1);
+
+        start_scanline(&mmc5, &vram);
+        start_scanline(&mmc5, &vram);
+        assert_eq!(mmc5.peek8(0x5204) & 0x80, 0x80);
+        assert!(!mmc5.irq());
+    }
+
+    /* A length of 2 runs out on the second frame clock */
+    fn length_cycles(mmc5: &mut Mmc5) -> usize {
+        mmc5.write8(0x5015, 0x01);
+        mmc5.write8(0x5003, 0x18);
+
+        let mut cycles = 0;
+        while mmc5.peek8(0x5015) & 0x01 != 0 {
+            mmc5.clock();
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn frame_period() {
+        assert_eq!(length_cycles(&mut mmc5()), 2 * 7457);
+
+        let mut pal = mmc5();
+        pal.set_region(Region::Pal);
+        pal.power_on();
+        assert_eq!(length_cycles(&mut pal), 2 * 8313);
+
+        let mut dendy = mmc5();
+        dendy.set_region(Region::Dendy);
+        assert_eq!(length_cycles(&mut dendy), 2 * 7457);
+    }
+
+    #[test]
+    fn multiplier() {
+        let mut mmc5 = mmc5();
+        assert_eq!((mmc5.read8(0x5205), mmc5.read8(0x5206)), (0x01, 0xfe));
+
+        mmc5.write8(0x5205, 12);
+        mmc5.write8(0x5206, 34);
+        assert_eq!((mmc5.read8(0x5205), mmc5.read8(0x5206)), (0x98, 0x01));
+    }
+}
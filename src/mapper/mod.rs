@@ -0,0 +1,74 @@
+use cartridge::{Cartridge, Error, Rom};
+
+pub mod mmc5;
+pub mod nrom;
+
+/* Boxed, so boards can be registered with closures that capture state */
+pub type Constructor = Box<dyn Fn(Rom) -> Result<Box<Cartridge>, Error>>;
+
+pub struct Entry {
+    pub mapper: u16,
+    /* None accepts any submapper */
+    pub submapper: Option<u8>,
+    pub name: &'static str,
+    pub constructor: Constructor,
+}
+
+/*
+ * Maps iNES mapper numbers to boards. Registry::default() knows about the
+ * boards in this crate, anything else can be added with register().
+ */
+pub struct Registry {
+    entries: Vec<Entry>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            entries: Vec::new(),
+        }
+    }
+
+    /* Registering the same key twice replaces the previous entry */
+    pub fn register<F>(&mut self, mapper: u16, submapper: Option<u8>, name: &'static str, constructor: F)
+        where F: Fn(Rom) -> Result<Box<Cartridge>, Error> + 'static
+    {
+        self.entries.retain(|entry| entry.mapper != mapper || entry.submapper != submapper);
+        self.entries.push(Entry {
+            mapper,
+            submapper,
+            name,
+            constructor: Box::new(constructor),
+        });
+        self.entries.sort_by_key(|entry| (entry.mapper, entry.submapper));
+    }
+
+    pub fn find(&self, mapper: u16, submapper: u8) -> Option<&Entry> {
+        let exact = self.entries.iter()
+            .find(|entry| entry.mapper == mapper && entry.submapper == Some(submapper));
+        exact.or_else(|| self.entries.iter()
+            .find(|entry| entry.mapper == mapper && entry.submapper.is_none()))
+    }
+
+    pub fn build(&self, rom: Rom) -> Result<Box<Cartridge>, Error> {
+        let mapper = rom.header.mapper;
+        let submapper = rom.header.submapper;
+        match self.find(mapper, submapper) {
+            Some(entry) => (entry.constructor)(rom),
+            None => Err(Error::UnsupportedMapper(mapper, submapper)),
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        let mut registry = Registry::new();
+        nrom::register(&mut registry);
+        mmc5::register(&mut registry);
+        registry
+    }
+}
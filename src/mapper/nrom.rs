@@ -0,0 +1,80 @@
+use cartridge::{Mapper, Mirroring, Rom};
+use mapper::Registry;
+use mem::Mem;
+use ram::{self, RamInit};
+
+pub fn register(registry: &mut Registry) {
+    registry.register(0, None, "NROM", |rom| Ok(Box::new(Nrom::new(rom))));
+}
+
+const PRG_RAM_SIZE: usize = 0x2000;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+pub struct Nrom {
+    prg_rom: Box<[u8]>,
+    prg_ram: Box<[u8]>,
+    chr: Box<[u8]>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Nrom {
+        /* Boards without CHR ROM have 8K of CHR RAM instead */
+        let chr_is_ram = rom.chr_rom.is_empty();
+        let chr = if chr_is_ram {
+            vec![0; CHR_RAM_SIZE].into_boxed_slice()
+        } else {
+            rom.chr_rom
+        };
+
+        Nrom {
+            prg_rom: rom.prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE].into_boxed_slice(),
+            chr,
+            chr_is_ram,
+            mirroring: rom.header.mirroring,
+        }
+    }
+}
+
+impl Mem for Nrom {
+    fn peek8(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000 ..= 0x7fff => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000 ..= 0xFFFF =>
+                self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()],
+            _ => 0,
+        }
+    }
+
+    fn write8(&mut self, addr: u16, val: u8) {
+        if let 0x6000 ..= 0x7fff = addr {
+            self.prg_ram[(addr - 0x6000) as usize] = val;
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn chr_read8(&self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn chr_write8(&mut self, addr: u16, val: u8) {
+        if self.chr_is_ram {
+            self.chr[addr as usize % CHR_RAM_SIZE] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn init_ram(&mut self, init: RamInit) {
+        init.fill(&mut self.prg_ram, ram::STREAM_WRAM);
+    }
+
+    fn drives_cpu_bus(&self, addr: u16) -> bool {
+        addr >= 0x6000
+    }
+}
@@ -1,38 +1,391 @@
+use apu::{Apu, SAMPLE_RATE};
 use cartridge::Cartridge;
+use controller::Controller;
+use ppu::Ppu;
+use ram::{self, RamInit};
+use region::Region;
 
 pub trait Mem {
-    fn read8(&self, addr: u16) -> u8;
+    /* Reads as seen on the bus, which can clear flags or shift registers */
+    fn read8(&mut self, addr: u16) -> u8 {
+        self.peek8(addr)
+    }
+
+    /* The same value without the side effects, for debuggers and tracing */
+    fn peek8(&self, addr: u16) -> u8;
+
     fn write8(&mut self, addr: u16, val: u8);
+
+    /* Called before every CPU bus access, to run the rest of the system */
+    fn tick(&mut self) {}
+
+    /* NMI is edge triggered, so this acknowledges it */
+    fn poll_nmi(&mut self) -> bool {
+        false
+    }
+
+    fn poll_irq(&self) -> bool {
+        false
+    }
+
+    /*
+     * DMA halts the CPU on its next read. This runs any pending transfer
+     * and returns the number of cycles the CPU was stalled for.
+     */
+    fn dma(&mut self) -> usize {
+        0
+    }
+
+    /*
+     * Where the PPU is as (scanline, dot), for traces. The pre-render
+     * line is -1, like in Nintendulator's logs.
+     */
+    fn ppu_position(&self) -> Option<(i16, u16)> {
+        None
+    }
+
+    /* Emulators power up at different points in the frame, this lines traces up */
+    fn set_ppu_position(&mut self, _scanline: i16, _dot: u16) {}
+
+    /* The reset button, for everything on the bus that sees it */
+    fn soft_reset(&mut self) {}
 }
 
-pub struct MemMap<'a> {
+pub struct MemMap {
     ram: [u8; 0x0800],
-    cartridge: &'a Cartridge,
+    cartridge: Box<Cartridge>,
+    controllers: [Controller; 2],
+    ppu: Ppu,
+    apu: Apu,
+
+    /* The last value on the data bus, which unmapped reads return */
+    open_bus: u8,
+
+    /*
+     * Everything is driven off one master clock, the CPU and PPU run at
+     * fixed dividers of it (see Region), so on NTSC there are exactly 3
+     * dots per CPU cycle and on PAL 3.2.
+     */
+    region: Region,
+    master_clock: u64,
+    ppu_clock: u64,
+
+    /* Page written to $4014, copied on the CPU's next read */
+    oam_dma: Option<u8>,
 }
 
-impl<'a> MemMap<'a> {
-    pub fn new(cartridge: &'a Cartridge) -> MemMap<'a> {
+impl MemMap {
+    pub fn new(cartridge: Box<Cartridge>) -> MemMap {
+        MemMap::with_region(cartridge, Region::default())
+    }
+
+    pub fn with_region(mut cartridge: Box<Cartridge>, region: Region) -> MemMap {
+        cartridge.set_region(region);
         MemMap {
             ram: [0; 0x0800],
-            cartridge: cartridge,
+            cartridge,
+            controllers: [Controller::new(), Controller::new()],
+            ppu: Ppu::with_region(region),
+            apu: Apu::with_region(region, SAMPLE_RATE),
+
+            open_bus: 0,
+
+            region,
+            master_clock: 0,
+            ppu_clock: 0,
+
+            oam_dma: None,
+        }
+    }
+
+    /* Everything back to its power on state, with RAM filled using `init` */
+    pub fn power_on(&mut self, init: RamInit) {
+        self.ppu = Ppu::with_region(self.region);
+        self.apu = Apu::with_region(self.region, self.apu.sample_rate());
+        self.master_clock = 0;
+        self.ppu_clock = 0;
+        self.oam_dma = None;
+        self.open_bus = 0;
+        self.cartridge.power_on();
+        self.init_ram(init);
+    }
+
+    /* Sets RAM, cartridge PRG RAM and OAM to what they'd hold at power on */
+    pub fn init_ram(&mut self, init: RamInit) {
+        init.fill(&mut self.ram, ram::STREAM_RAM);
+        self.cartridge.init_ram(init);
+        self.ppu.init_oam(init);
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        self.cartridge.as_ref()
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        self.cartridge.as_mut()
+    }
+
+    pub fn controller(&self, port: usize) -> &Controller {
+        &self.controllers[port]
+    }
+
+    pub fn controller_mut(&mut self, port: usize) -> &mut Controller {
+        &mut self.controllers[port]
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn master_clock(&self) -> u64 {
+        self.master_clock
+    }
+
+    fn cpu_cycle(&self) -> u64 {
+        self.master_clock / self.region.cpu_divider()
+    }
+
+    /* DMC fetches take 4 cycles: halt, dummy, alignment and the read itself */
+    fn dmc_dma(&mut self, addr: u16) {
+        for _ in 0..3 {
+            self.tick();
+        }
+        let val = self.dma_read(addr);
+        self.apu.dmc_fill(val);
+    }
+
+    fn dma_read(&mut self, addr: u16) -> u8 {
+        self.tick();
+        self.read8(addr)
+    }
+
+    /*
+     * OAM DMA takes 513 cycles, plus one to line up with a read cycle if
+     * it started on an odd one. A DMC fetch landing in the middle of it
+     * only steals 2 cycles, since the CPU is already halted.
+     */
+    fn oam_dma(&mut self, page: u8) {
+        self.tick();
+        if self.cpu_cycle() & 1 == 1 {
+            self.tick();
+        }
+
+        for i in 0..256 {
+            if let Some(addr) = self.apu.dmc_fetch_addr() {
+                let val = self.dma_read(addr);
+                self.apu.dmc_fill(val);
+                self.tick();
+            }
+
+            let val = self.dma_read((page as u16) << 8 | i);
+            self.tick();
+            self.ppu.write_register(0x2004, val, self.cartridge.as_mut());
         }
     }
 }
 
-impl<'a> Mem for MemMap<'a> {
-    fn read8(&self, addr: u16) -> u8 {
+impl Mem for MemMap {
+    fn read8(&mut self, addr: u16) -> u8 {
+        let open_bus = self.open_bus;
+        let val = match addr {
+            0x0000 ..= 0x1fff => self.ram[(addr % 0x0800) as usize],
+            0x2000 ..= 0x3fff => self.ppu.read_register(addr, self.cartridge.as_ref()),
+
+            /* $4015 is inside the CPU, so reading it doesn't touch the data bus */
+            0x4015 => return self.apu.read_status() | open_bus & 0x20,
+
+            /* Controllers only drive the low bits */
+            0x4016 => self.controllers[0].read() | open_bus & 0xe0,
+            0x4017 => self.controllers[1].read() | open_bus & 0xe0,
+            0x4020 ..= 0xffff if self.cartridge.drives_cpu_bus(addr) => self.cartridge.read8(addr),
+            _ => open_bus,
+        };
+
+        self.open_bus = val;
+        val
+    }
+
+    fn peek8(&self, addr: u16) -> u8 {
+        let open_bus = self.open_bus;
         match addr {
-            0x0000 ... 0x1fff => self.ram[(addr % 0x0800) as usize],
-            0x4020 ... 0xffff => self.cartridge.read8(addr),
-            _ => panic!("not yet implemented MemMap::read8(0x{:04X})", addr),
+            0x0000 ..= 0x1fff => self.ram[(addr % 0x0800) as usize],
+            0x2000 ..= 0x3fff => self.ppu.peek_register(addr),
+            0x4015 => self.apu.peek_status() | open_bus & 0x20,
+            0x4016 => self.controllers[0].peek() | open_bus & 0xe0,
+            0x4017 => self.controllers[1].peek() | open_bus & 0xe0,
+            0x4020 ..= 0xffff if self.cartridge.drives_cpu_bus(addr) => self.cartridge.peek8(addr),
+            _ => open_bus,
         }
     }
 
     fn write8(&mut self, addr: u16, val: u8) {
+        self.open_bus = val;
         match addr {
-            0x0000 ... 0x1fff => self.ram[(addr % 0x0800) as usize] = val,
-            0x4020 ... 0xffff => self.write8(addr, val),
-            _ => panic!("not yet implemented MemMap::write8(0x{:04X},0x{:02X})", addr, val),
+            0x0000 ..= 0x1fff => self.ram[(addr % 0x0800) as usize] = val,
+            0x2000 ..= 0x3fff => {
+                self.ppu.write_register(addr, val, self.cartridge.as_mut());
+                self.cartridge.snoop_write8(addr, val);
+            },
+            0x4014 => self.oam_dma = Some(val),
+            0x4000 ..= 0x4013 | 0x4015 | 0x4017 => {
+                self.apu.write8(addr, val);
+                self.cartridge.snoop_write8(addr, val);
+            },
+            0x4016 => for controller in &mut self.controllers {
+                controller.write_strobe(val);
+            },
+            0x4020 ..= 0xffff => self.cartridge.write8(addr, val),
+            _ => {},
+        }
+    }
+
+    fn ppu_position(&self) -> Option<(i16, u16)> {
+        let scanline = match self.ppu.scanline() {
+            line if line == self.region.scanlines() - 1 => -1,
+            line => line as i16,
+        };
+        Some((scanline, self.ppu.dot()))
+    }
+
+    fn set_ppu_position(&mut self, scanline: i16, dot: u16) {
+        let scanline = if scanline < 0 { self.region.scanlines() - 1 } else { scanline as u16 };
+        self.ppu.set_position(scanline, dot);
+    }
+
+    fn soft_reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.cartridge.reset();
+        self.oam_dma = None;
+    }
+
+    fn tick(&mut self) {
+        self.master_clock += self.region.cpu_divider();
+
+        self.cartridge.clock();
+        self.apu.clock(self.cartridge.audio());
+
+        let ppu_divider = self.region.ppu_divider();
+        while self.ppu_clock + ppu_divider <= self.master_clock {
+            self.ppu_clock += ppu_divider;
+            self.ppu.step(self.cartridge.as_ref());
+        }
+    }
+
+    fn poll_nmi(&mut self) -> bool {
+        self.ppu.take_nmi()
+    }
+
+    fn poll_irq(&self) -> bool {
+        self.apu.irq() || self.cartridge.irq()
+    }
+
+    fn dma(&mut self) -> usize {
+        let start = self.cpu_cycle();
+
+        if let Some(page) = self.oam_dma.take() {
+            self.oam_dma(page);
+        }
+
+        if let Some(addr) = self.apu.dmc_fetch_addr() {
+            self.dmc_dma(addr);
+        }
+
+        (self.cpu_cycle() - start) as usize
+    }
+}
+
+/* One CPU bus access, as recorded by FlatMem */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BusCycle {
+    pub addr: u16,
+    pub val: u8,
+    pub write: bool,
+}
+
+/*
+ * 64K of RAM and nothing else, for running CPU tests written for a bare
+ * 6502. It can keep a log of every bus access, to check cycle by cycle.
+ */
+pub struct FlatMem {
+    ram: Box<[u8]>,
+    cycles: Option<Vec<BusCycle>>,
+}
+
+impl FlatMem {
+    pub fn new() -> FlatMem {
+        FlatMem {
+            ram: vec![0; 0x10000].into_boxed_slice(),
+            cycles: None,
+        }
+    }
+
+    /* Copies `data` in at `addr`, wrapping around at the top of memory */
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            self.ram[addr.wrapping_add(i as u16) as usize] = byte;
+        }
+    }
+
+    /* Starts logging bus accesses, or stops and throws the log away */
+    pub fn record_cycles(&mut self, record: bool) {
+        self.cycles = if record { Some(Vec::new()) } else { None };
+    }
+
+    pub fn cycles(&self) -> &[BusCycle] {
+        self.cycles.as_ref().map_or(&[], Vec::as_slice)
+    }
+
+    pub fn clear_cycles(&mut self) {
+        if let Some(ref mut cycles) = self.cycles {
+            cycles.clear();
+        }
+    }
+}
+
+impl Default for FlatMem {
+    fn default() -> FlatMem {
+        FlatMem::new()
+    }
+}
+
+impl Mem for FlatMem {
+    fn read8(&mut self, addr: u16) -> u8 {
+        let val = self.ram[addr as usize];
+        if let Some(ref mut cycles) = self.cycles {
+            cycles.push(BusCycle {
+                addr,
+                val,
+                write: false,
+            });
+        }
+        val
+    }
+
+    fn peek8(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn write8(&mut self, addr: u16, val: u8) {
+        self.ram[addr as usize] = val;
+        if let Some(ref mut cycles) = self.cycles {
+            cycles.push(BusCycle {
+                addr,
+                val,
+                write: true,
+            });
         }
     }
 }
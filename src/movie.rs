@@ -0,0 +1,114 @@
+use controller::*;
+use nes::Nes;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/*
+ * Input movies in FCEUX's FM2 text format. Only the input log is used:
+ * one line per frame with the commands, then one field per port.
+ *
+ *   |0|R.D....A|........||
+ *
+ * References:
+ *  - http://www.fceux.com/web/help/fceux.html?fm2.html
+ */
+
+pub const COMMAND_SOFT_RESET: u8 = 1 << 0;
+pub const COMMAND_POWER_ON:   u8 = 1 << 1;
+
+/* The order FM2 lists buttons in */
+const FM2_BUTTONS: [u8; 8] = [
+    BUTTON_RIGHT, BUTTON_LEFT, BUTTON_DOWN, BUTTON_UP,
+    BUTTON_START, BUTTON_SELECT, BUTTON_B, BUTTON_A,
+];
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub commands: u8,
+    pub buttons: [u8; 2],
+}
+
+impl MovieFrame {
+    /* Call before running the frame */
+    pub fn apply(&self, nes: &mut Nes) {
+        if self.commands & COMMAND_POWER_ON != 0 {
+            nes.power_on();
+        } else if self.commands & COMMAND_SOFT_RESET != 0 {
+            nes.soft_reset();
+        }
+
+        for (port, &buttons) in self.buttons.iter().enumerate() {
+            nes.set_input(port, buttons);
+        }
+    }
+}
+
+pub struct Movie {
+    frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn parse(text: &str) -> io::Result<Movie> {
+        let mut frames = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if !line.starts_with('|') {
+                continue;
+            }
+
+            match parse_frame(line) {
+                Some(frame) => frames.push(frame),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("bad movie frame on line {}", i + 1))),
+            }
+        }
+
+        Ok(Movie {
+            frames,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Movie> {
+        Movie::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn frame(&self, frame: usize) -> Option<&MovieFrame> {
+        self.frames.get(frame)
+    }
+}
+
+fn parse_frame(line: &str) -> Option<MovieFrame> {
+    let mut fields = line.split('|').skip(1);
+    let commands = fields.next()?.trim().parse().ok()?;
+
+    let mut buttons = [0; 2];
+    for port in &mut buttons {
+        let field = fields.next().unwrap_or("");
+        if field.is_empty() {
+            continue;
+        }
+        if field.len() != FM2_BUTTONS.len() {
+            return None;
+        }
+
+        for (c, &button) in field.chars().zip(FM2_BUTTONS.iter()) {
+            if c != '.' && c != ' ' {
+                *port |= button;
+            }
+        }
+    }
+
+    Some(MovieFrame {
+        commands,
+        buttons,
+    })
+}
@@ -0,0 +1,242 @@
+use cartridge::{self, Cartridge, Rom};
+use cpu::Cpu;
+use gamedb::GameDb;
+use image::{self, ImageFormat};
+use mapper::Registry;
+use mem::MemMap;
+use palette::Palette;
+use ram::RamInit;
+use record::Recorder;
+use region::Region;
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+pub use ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
+pub struct Nes {
+    cpu: Cpu<MemMap>,
+    ram_init: RamInit,
+    palette: Palette,
+    recorder: Option<Recorder>,
+    record_error: Option<io::Error>,
+}
+
+impl Nes {
+    pub fn new(cartridge: Box<Cartridge>) -> Nes {
+        Nes::with_region(cartridge, Region::default())
+    }
+
+    pub fn with_region(cartridge: Box<Cartridge>, region: Region) -> Nes {
+        let mut nes = Nes {
+            cpu: Cpu::new(MemMap::with_region(cartridge, region)),
+            ram_init: RamInit::default(),
+            palette: Palette::default(),
+            recorder: None,
+            record_error: None,
+        };
+        nes.power_on();
+        nes
+    }
+
+    pub fn load_rom<P: AsRef<Path>>(path: P) -> Result<Nes, cartridge::Error> {
+        Nes::load_rom_with(path, None, &GameDb::new())
+    }
+
+    /*
+     * A forced `region` wins, otherwise it comes from the NES 2.0 header,
+     * then the game database, and NTSC if neither knows.
+     */
+    pub fn load_rom_with<P: AsRef<Path>>(path: P, region: Option<Region>, db: &GameDb)
+                                         -> Result<Nes, cartridge::Error> {
+        let mut file = File::open(path)?;
+        Nes::from_rom(Rom::new(&mut file)?, region, db)
+    }
+
+    /* Like load_rom_with(), for a ROM that's already been read */
+    pub fn from_rom(rom: Rom, region: Option<Region>, db: &GameDb) -> Result<Nes, cartridge::Error> {
+        let region = region
+            .or(rom.header.region)
+            .or_else(|| db.region(rom.crc32()))
+            .unwrap_or_default();
+        let cartridge = Registry::default().build(rom)?;
+        Ok(Nes::with_region(cartridge, region))
+    }
+
+    pub fn ram_init(&self) -> RamInit {
+        self.ram_init
+    }
+
+    /* Takes effect on the next power_on() */
+    pub fn set_ram_init(&mut self, init: RamInit) {
+        self.ram_init = init;
+    }
+
+    /* Power cycles the console, RAM is refilled according to ram_init() */
+    pub fn power_on(&mut self) {
+        let ram_init = self.ram_init;
+        self.cpu.mem_mut().power_on(ram_init);
+        self.cpu.power_on();
+    }
+
+    /* The reset button: RAM and most CPU registers survive it */
+    pub fn soft_reset(&mut self) {
+        self.cpu.soft_reset();
+    }
+
+    /*
+     * The CPU drives the scheduler: every bus access it makes runs the
+     * PPU, APU and cartridge up to the same point on the master clock.
+     */
+    pub fn step_instruction(&mut self) {
+        self.cpu.step();
+    }
+
+    /* Runs whole instructions until at least `cycles` CPU cycles have passed */
+    pub fn run_cycles(&mut self, cycles: usize) {
+        let end = self.cpu.clock() + cycles;
+        while self.cpu.clock() < end {
+            self.cpu.step();
+        }
+    }
+
+    /* Runs until the PPU enters vblank, finishing the current instruction */
+    pub fn run_until_vblank(&mut self) {
+        let frame = self.frame();
+        while self.frame() == frame {
+            self.cpu.step();
+        }
+    }
+
+    pub fn run_frame(&mut self) {
+        self.cpu.mem_mut().apu_mut().clear_samples();
+        self.run_until_vblank();
+        self.record_frame();
+    }
+
+    /* Every frame from run_frame() goes to `recorder` until stop_recording() */
+    pub fn start_recording(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+        self.record_error = None;
+    }
+
+    /* Finishes the recording, returning the first error hit while writing it */
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        let result = match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        };
+
+        match self.record_error.take() {
+            Some(err) => Err(err),
+            None => result,
+        }
+    }
+
+    /*
+     * Recording stops at the first error, it's reported by stop_recording().
+     * What was written before it is still finished, so hitting a size limit
+     * leaves a playable file.
+     */
+    fn record_frame(&mut self) {
+        if self.recorder.is_none() {
+            return;
+        }
+
+        let rgb = self.rgb();
+        let result = match self.recorder {
+            Some(ref mut recorder) => recorder.add_frame(&rgb, self.cpu.mem().apu().samples()),
+            None => Ok(()),
+        };
+
+        if let Err(err) = result {
+            if let Some(recorder) = self.recorder.take() {
+                let _ = recorder.finish();
+            }
+            self.record_error = Some(err);
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.cpu.mem().region()
+    }
+
+    pub fn frame(&self) -> usize {
+        self.cpu.mem().ppu().frame()
+    }
+
+    /* Palette indices with emphasis in bits 6-8, SCREEN_WIDTH * SCREEN_HEIGHT */
+    pub fn framebuffer(&self) -> &[u16] {
+        self.cpu.mem().ppu().framebuffer()
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    /* The current frame as RGB, 3 bytes per pixel */
+    pub fn rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        for &index in self.framebuffer() {
+            rgb.extend_from_slice(&self.palette.rgb(index));
+        }
+        rgb
+    }
+
+    /* Encodes the current frame, PNGs get the frame number in a tEXt chunk */
+    pub fn screenshot(&self, format: ImageFormat) -> Vec<u8> {
+        let rgb = self.rgb();
+        match format {
+            ImageFormat::Png => {
+                let frame = self.frame().to_string();
+                image::encode_png(SCREEN_WIDTH, SCREEN_HEIGHT, &rgb, &[("Frame", &frame)])
+            },
+            ImageFormat::Ppm => image::encode_ppm(SCREEN_WIDTH, SCREEN_HEIGHT, &rgb),
+        }
+    }
+
+    /* Samples produced since the start of the last run_frame() */
+    pub fn audio(&self) -> &[f32] {
+        self.cpu.mem().apu().samples()
+    }
+
+    /* See the BUTTON_* constants in the controller module */
+    pub fn set_input(&mut self, port: usize, buttons: u8) {
+        self.cpu.mem_mut().controller_mut(port).set_buttons(buttons);
+    }
+
+    pub fn cpu(&self) -> &Cpu<MemMap> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu<MemMap> {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* An NROM game with 16K of PRG ROM and 8K of CHR ROM, and no region in the header */
+    fn rom() -> Rom {
+        let mut file = b"NES\x1a\x01\x01".to_vec();
+        file.extend_from_slice(&[0; 10]);
+        file.extend(vec![0xea; 0x4000]);
+        file.extend(vec![0x55; 0x2000]);
+        Rom::new(&mut file.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn region_from_gamedb() {
+        let db = GameDb::parse(&format!("# crc32 region name\n{:08x} pal Test (Europe)\n", rom().crc32())).unwrap();
+        assert_eq!(Nes::from_rom(rom(), None, &db).unwrap().region(), Region::Pal);
+        assert_eq!(Nes::from_rom(rom(), Some(Region::Dendy), &db).unwrap().region(), Region::Dendy);
+        assert_eq!(Nes::from_rom(rom(), None, &GameDb::new()).unwrap().region(), Region::Ntsc);
+    }
+}
@@ -0,0 +1,133 @@
+use palette::{self, NtscParams};
+use ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
+/*
+ * Simulates the composite video signal instead of mapping palette entries
+ * straight to RGB. Every PPU pixel is 8 samples of a 12 phase color
+ * subcarrier, and a pixel's neighbours bleed into its color when the
+ * signal is decoded, which gives the artifact colors and dot crawl.
+ *
+ * References:
+ *  - http://wiki.nesdev.com/w/index.php/NTSC_video
+ */
+
+/* Samples per PPU pixel */
+const SAMPLES: usize = 8;
+
+/* The scanline's 341 dots move the subcarrier 2728 samples, or 4 phases, per line */
+const LINE_PHASE: usize = 4;
+
+/* Default output width, close to the 8:7 pixel aspect ratio of a TV */
+pub const NTSC_WIDTH: usize = 602;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NtscPreset {
+    /* The blurriest, as from the RF modulator */
+    Rf,
+    Composite,
+    /* Luma and chroma on separate wires, so no dot crawl */
+    SVideo,
+}
+
+impl NtscPreset {
+    /* Sample windows the luma and chroma are averaged over, wider is blurrier */
+    fn windows(self) -> (usize, usize) {
+        match self {
+            NtscPreset::Rf => (24, 48),
+            NtscPreset::Composite => (12, 24),
+            NtscPreset::SVideo => (4, 24),
+        }
+    }
+}
+
+pub struct NtscFilter {
+    preset: NtscPreset,
+    params: NtscParams,
+    width: usize,
+}
+
+impl NtscFilter {
+    pub fn new(preset: NtscPreset) -> NtscFilter {
+        NtscFilter::with_params(preset, NtscParams::default(), NTSC_WIDTH)
+    }
+
+    pub fn with_params(preset: NtscPreset, params: NtscParams, width: usize) -> NtscFilter {
+        NtscFilter {
+            preset,
+            params,
+            width,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        SCREEN_HEIGHT
+    }
+
+    /*
+     * Converts a PPU framebuffer to RGBA8888, `out` needs width() * height()
+     * * 4 bytes. The frame number moves the subcarrier, like on hardware.
+     */
+    pub fn write_rgba(&self, framebuffer: &[u16], frame: usize, out: &mut [u8]) {
+        let line_samples = SCREEN_WIDTH * SAMPLES;
+        let (luma_window, chroma_window) = self.preset.windows();
+
+        let mut luma = vec![0.0; line_samples + 1];
+        let mut i_sum = vec![0.0; line_samples + 1];
+        let mut q_sum = vec![0.0; line_samples + 1];
+
+        /* The subcarrier phase at the start of each frame cycles through 3 values */
+        let frame_phase = (frame % 3) * LINE_PHASE;
+
+        for y in 0..SCREEN_HEIGHT {
+            let line = &framebuffer[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH];
+            let line_phase = frame_phase + y * LINE_PHASE;
+
+            /* Running sums, so any window can be averaged with one subtraction */
+            let mut pixel_luma = 0.0;
+            for sample in 0..line_samples {
+                let index = line[sample / SAMPLES];
+                let phase = ((line_phase + sample) % 12) as u16;
+                let signal = palette::signal(index, phase);
+                let (sin, cos) = palette::phase_angle(phase, &self.params).sin_cos();
+
+                /* S-Video luma is the average over a full cycle, without the subcarrier */
+                if self.preset == NtscPreset::SVideo && sample % SAMPLES == 0 {
+                    pixel_luma = (0..12).map(|phase| palette::signal(index, phase)).sum::<f32>() / 12.0;
+                }
+                let luma_signal = if self.preset == NtscPreset::SVideo { pixel_luma } else { signal };
+
+                luma[sample + 1] = luma[sample] + luma_signal;
+                i_sum[sample + 1] = i_sum[sample] + signal * cos;
+                q_sum[sample + 1] = q_sum[sample] + signal * sin;
+            }
+
+            let average = |sums: &[f32], center: usize, window: usize| {
+                let start = center.saturating_sub(window / 2);
+                let end = (start + window).min(line_samples);
+                (sums[end] - sums[start]) / (end - start) as f32
+            };
+
+            for x in 0..self.width {
+                let center = (x * line_samples + line_samples / 2) / self.width;
+                let [r, g, b] = palette::yiq_to_rgb(
+                    average(&luma, center, luma_window),
+                    average(&i_sum, center, chroma_window),
+                    average(&q_sum, center, chroma_window),
+                    &self.params);
+
+                let offset = (y * self.width + x) * 4;
+                out[offset..offset + 4].copy_from_slice(&[r, g, b, 0xff]);
+            }
+        }
+    }
+
+    pub fn rgba(&self, framebuffer: &[u16], frame: usize) -> Vec<u8> {
+        let mut out = vec![0; self.width * SCREEN_HEIGHT * 4];
+        self.write_rgba(framebuffer, frame, &mut out);
+        out
+    }
+}
@@ -41,6 +41,7 @@ impl From<u8> for AddressingMode {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug)]
 pub enum Instruction {
     LDA, LDX, LDY, STA, STX, STY, TAX, TAY,
@@ -57,6 +58,31 @@ pub enum Instruction {
     STP, TAS, XAA,
 }
 
+impl Instruction {
+    /* Stores and read-modify-writes, which always take the indexing penalty */
+    pub fn writes(self) -> bool {
+        matches!(self,
+            Instruction::STA | Instruction::STX | Instruction::STY |
+            Instruction::ASL | Instruction::LSR | Instruction::ROL | Instruction::ROR |
+            Instruction::INC | Instruction::DEC |
+            Instruction::SAX | Instruction::AHX | Instruction::SHX | Instruction::SHY |
+            Instruction::TAS | Instruction::SLO | Instruction::RLA | Instruction::SRE |
+            Instruction::RRA | Instruction::DCP | Instruction::ISC)
+    }
+}
+
+/*
+ * Opcodes outside the documented 151. Besides the unofficial instructions
+ * that covers the extra NOPs and $EB, a copy of SBC #imm.
+ */
+pub fn is_unofficial(opcode: u8) -> bool {
+    match Instruction::from(opcode) {
+        Instruction::NOP => opcode != 0xea,
+        Instruction::SBC => opcode == 0xeb,
+        instruction => instruction as u8 >= Instruction::AHX as u8,
+    }
+}
+
 impl From<u8> for Instruction {
     fn from(opcode: u8) -> Self {
         INSTRUCTION_MAP[opcode as usize]
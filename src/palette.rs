@@ -0,0 +1,219 @@
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/*
+ * References:
+ *  - http://wiki.nesdev.com/w/index.php/NTSC_video
+ *  - http://wiki.nesdev.com/w/index.php/PPU_palettes
+ *
+ * Palettes have 512 entries, indexed the same way as the PPU framebuffer:
+ * the 6 bit palette index with the $2001 emphasis bits above it.
+ */
+
+pub const PALETTE_SIZE: usize = 512;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /* .pal files have 64 or 512 RGB triplets */
+    BadSize(usize),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/* Knobs for the generated palette, the defaults approximate a 2C02 on a typical TV */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NtscParams {
+    /* Degrees */
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    pub gamma: f32,
+}
+
+impl Default for NtscParams {
+    fn default() -> NtscParams {
+        NtscParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+/* Composite signal levels for luma 0-3, low then high, relative to sync */
+const LEVELS: [f32; 8] = [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+const ATTENUATION: f32 = 0.746;
+
+/* Emphasis is done by darkening the signal during 6 of the 12 color phases */
+const EMPHASIS_PHASES: [u8; 3] = [0, 4, 8];
+
+/* Makes the default hue line up with the usual 2C02 captures */
+const HUE_OFFSET: f32 = 120.0;
+
+pub struct Palette {
+    colors: Box<[[u8; 3]]>,
+}
+
+impl Palette {
+    pub fn generate(params: &NtscParams) -> Palette {
+        let colors = (0..PALETTE_SIZE)
+            .map(|index| ntsc_color(index as u16, params))
+            .collect::<Vec<_>>();
+        Palette {
+            colors: colors.into_boxed_slice(),
+        }
+    }
+
+    /* 64 entry files get their emphasis colors by tinting the base ones */
+    pub fn from_pal(data: &[u8]) -> Result<Palette, Error> {
+        let entries = data.len() / 3;
+        if !data.len().is_multiple_of(3) || (entries != 64 && entries != PALETTE_SIZE) {
+            return Err(Error::BadSize(data.len()));
+        }
+
+        let colors = (0..PALETTE_SIZE).map(|index| {
+            if entries == PALETTE_SIZE {
+                let offset = index * 3;
+                [data[offset], data[offset + 1], data[offset + 2]]
+            } else {
+                let offset = (index & 0x3f) * 3;
+                let rgb = [data[offset], data[offset + 1], data[offset + 2]];
+                tint(rgb, index as u16)
+            }
+        }).collect::<Vec<_>>();
+
+        Ok(Palette {
+            colors: colors.into_boxed_slice(),
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Palette, Error> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        Palette::from_pal(&data)
+    }
+
+    pub fn rgb(&self, index: u16) -> [u8; 3] {
+        self.colors[index as usize % PALETTE_SIZE]
+    }
+
+    /* Converts a PPU framebuffer into RGBA8888, `out` needs 4 bytes per pixel */
+    pub fn write_rgba(&self, framebuffer: &[u16], out: &mut [u8]) {
+        for (&index, pixel) in framebuffer.iter().zip(out.chunks_mut(4)) {
+            let [r, g, b] = self.rgb(index);
+            pixel.copy_from_slice(&[r, g, b, 0xff]);
+        }
+    }
+
+    pub fn rgba(&self, framebuffer: &[u16]) -> Vec<u8> {
+        let mut out = vec![0; framebuffer.len() * 4];
+        self.write_rgba(framebuffer, &mut out);
+        out
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::generate(&NtscParams::default())
+    }
+}
+
+/* Decodes one palette entry the way a TV would, from a full color cycle */
+fn ntsc_color(index: u16, params: &NtscParams) -> [u8; 3] {
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let signal = signal(index, phase);
+        let (sin, cos) = phase_angle(phase, params).sin_cos();
+        y += signal;
+        i += signal * cos;
+        q += signal * sin;
+    }
+
+    yiq_to_rgb(y / 12.0, i / 12.0, q / 12.0, params)
+}
+
+/*
+ * The composite signal the PPU outputs for a palette entry at one of the
+ * 12 subcarrier phases, normalized so black is 0 and white is 1.
+ */
+pub fn signal(index: u16, phase: u16) -> f32 {
+    let color = index & 0x0f;
+    let level = if color > 0x0d { 1 } else { (index >> 4) & 0x03 };
+    let emphasis = (index >> 6) & 0x07;
+
+    let mut low = LEVELS[level as usize];
+    let mut high = LEVELS[4 + level as usize];
+    if color == 0x00 {
+        low = high;
+    }
+    if color > 0x0c {
+        high = low;
+    }
+
+    let in_phase = |color: u16| (color + phase) % 12 < 6;
+    let mut signal = if in_phase(color) { high } else { low };
+
+    if color < 0x0e {
+        for (bit, &emphasis_phase) in EMPHASIS_PHASES.iter().enumerate() {
+            if emphasis & (1 << bit) != 0 && in_phase(emphasis_phase as u16) {
+                signal *= ATTENUATION;
+            }
+        }
+    }
+
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+/* Angle of the color subcarrier at `phase`, in radians */
+pub fn phase_angle(phase: u16, params: &NtscParams) -> f32 {
+    (phase as f32 * 30.0 + params.hue + HUE_OFFSET) * PI / 180.0
+}
+
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32, params: &NtscParams) -> [u8; 3] {
+    let y = y * params.contrast + params.brightness;
+    let i = i * params.saturation;
+    let q = q * params.saturation;
+
+    /* FCC YIQ to RGB */
+    let r = y + 0.946882 * i + 0.623557 * q;
+    let g = y - 0.274788 * i - 0.635691 * q;
+    let b = y - 1.108545 * i + 1.709007 * q;
+
+    let correct = |value: f32| {
+        let value = value.clamp(0.0, 1.0);
+        (value.powf(2.2 / params.gamma) * 255.0 + 0.5) as u8
+    };
+    [correct(r), correct(g), correct(b)]
+}
+
+/* Emphasis darkens the two channels that aren't emphasized */
+fn tint(rgb: [u8; 3], index: u16) -> [u8; 3] {
+    let emphasis = (index >> 6) & 0x07;
+    if emphasis == 0 || index & 0x0e == 0x0e {
+        return rgb;
+    }
+
+    let mut out = [0; 3];
+    for (channel, value) in rgb.iter().enumerate() {
+        let mut value = *value as f32;
+        for bit in 0..3 {
+            if emphasis & (1 << bit) != 0 && bit != channel {
+                value *= ATTENUATION;
+            }
+        }
+        out[channel] = value as u8;
+    }
+    out
+}
@@ -0,0 +1,642 @@
+use cartridge::Cartridge;
+use ram::{self, RamInit};
+use region::Region;
+
+/*
+ * References:
+ *  - http://wiki.nesdev.com/w/index.php/PPU_rendering
+ *  - http://wiki.nesdev.com/w/index.php/PPU_scrolling
+ *  - http://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+ *
+ * Memory fetches are done in the same order and on the same dots as the
+ * real PPU, since mappers like the MMC5 watch them to follow rendering.
+ * A fetch takes two dots, the address is set up on the first one and
+ * the read happens on the second.
+ *
+ * The framebuffer holds palette indices with the emphasis bits from
+ * PPUMASK in bits 6-8, the same layout as a 512 entry palette. PAL's
+ * swapped red and green bits are put back in NTSC order.
+ */
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+pub const DOTS: u16 = 341;
+
+const CTRL_INCREMENT:    u8 = 1 << 2;
+const CTRL_SPRITE_TABLE: u8 = 1 << 3;
+const CTRL_BG_TABLE:     u8 = 1 << 4;
+const CTRL_SPRITE_SIZE:  u8 = 1 << 5;
+const CTRL_NMI:          u8 = 1 << 7;
+
+const MASK_GRAYSCALE:   u8 = 1 << 0;
+const MASK_BG_LEFT:     u8 = 1 << 1;
+const MASK_SPRITE_LEFT: u8 = 1 << 2;
+const MASK_BG:          u8 = 1 << 3;
+const MASK_SPRITES:     u8 = 1 << 4;
+
+const STATUS_OVERFLOW: u8 = 1 << 5;
+const STATUS_SPRITE0:  u8 = 1 << 6;
+const STATUS_VBLANK:   u8 = 1 << 7;
+
+#[derive(Copy, Clone, Default)]
+struct Sprite {
+    x: u8,
+    attr: u8,
+    low: u8,
+    high: u8,
+}
+
+pub struct Ppu {
+    region: Region,
+    vblank_line: u16,
+    pre_render_line: u16,
+
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+    oam: [u8; 256],
+
+    /* Loopy registers, see PPU_scrolling */
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
+
+    read_buffer: u8,
+    latch: u8,
+
+    vram: [u8; 0x0800],
+    palette: [u8; 32],
+
+    scanline: u16,
+    dot: u16,
+    frame: usize,
+    odd_frame: bool,
+    nmi_pending: bool,
+
+    /* Background pipeline */
+    bg_addr: u16,
+    nametable: u8,
+    attribute: u8,
+    bg_low: u8,
+    bg_high: u8,
+    bg_shift_low: u16,
+    bg_shift_high: u16,
+    attr_shift_low: u16,
+    attr_shift_high: u16,
+
+    /* Sprites for the current line, fetched during the previous one */
+    secondary_oam: [u8; 32],
+    sprites: [Sprite; 8],
+    sprite_count: usize,
+    sprite0_in_line: bool,
+
+    framebuffer: Box<[u16]>,
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu::with_region(Region::default())
+    }
+
+    pub fn with_region(region: Region) -> Ppu {
+        Ppu {
+            region,
+            vblank_line: region.vblank_line(),
+            pre_render_line: region.scanlines() - 1,
+
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            oam: [0; 256],
+
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+
+            read_buffer: 0,
+            latch: 0,
+
+            vram: [0; 0x0800],
+            palette: [0; 32],
+
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            odd_frame: false,
+            nmi_pending: false,
+
+            bg_addr: 0,
+            nametable: 0,
+            attribute: 0,
+            bg_low: 0,
+            bg_high: 0,
+            bg_shift_low: 0,
+            bg_shift_high: 0,
+            attr_shift_low: 0,
+            attr_shift_high: 0,
+
+            secondary_oam: [0xff; 32],
+            sprites: [Sprite::default(); 8],
+            sprite_count: 0,
+            sprite0_in_line: false,
+
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice(),
+        }
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    /* Moves the PPU without running it, for lining traces up with other emulators */
+    pub fn set_position(&mut self, scanline: u16, dot: u16) {
+        self.scanline = scanline;
+        self.dot = dot;
+    }
+
+    /* Incremented at the start of every vblank */
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    /* The reset line clears the write-only registers, memory is kept */
+    pub fn reset(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.t = 0;
+        self.x = 0;
+        self.w = false;
+        self.read_buffer = 0;
+        self.odd_frame = false;
+        self.nmi_pending = false;
+    }
+
+    pub fn init_oam(&mut self, init: RamInit) {
+        init.fill(&mut self.oam, ram::STREAM_OAM);
+    }
+
+    pub fn take_nmi(&mut self) -> bool {
+        let nmi = self.nmi_pending;
+        self.nmi_pending = false;
+        nmi
+    }
+
+    fn rendering(&self) -> bool {
+        self.mask & (MASK_BG | MASK_SPRITES) != 0
+    }
+
+    /* CPU interface, $2000-$2007 */
+    pub fn read_register(&mut self, addr: u16, cartridge: &Cartridge) -> u8 {
+        let val = match addr & 7 {
+            2 => {
+                let status = self.status;
+                self.status = status & !STATUS_VBLANK;
+                self.w = false;
+                status & 0xe0 | self.latch & 0x1f
+            },
+            4 => {
+                let val = self.oam[self.oam_addr as usize];
+                if self.oam_addr & 3 == 2 { val & 0xe3 } else { val }
+            },
+            7 => {
+                let v = self.v & 0x3fff;
+                let val = if v >= 0x3f00 {
+                    /* Palette reads are immediate, the buffer gets the nametable underneath */
+                    self.read_buffer = self.vram_read(v - 0x1000, cartridge);
+                    self.palette_read(v) | self.latch & 0xc0
+                } else {
+                    let val = self.read_buffer;
+                    self.read_buffer = self.vram_read(v, cartridge);
+                    val
+                };
+                self.increment_v();
+                val
+            },
+            _ => self.latch,
+        };
+
+        self.latch = val;
+        val
+    }
+
+    /* What read_register would return, without clearing vblank or touching VRAM */
+    pub fn peek_register(&self, addr: u16) -> u8 {
+        match addr & 7 {
+            2 => self.status & 0xe0 | self.latch & 0x1f,
+            4 => {
+                let val = self.oam[self.oam_addr as usize];
+                if self.oam_addr & 3 == 2 { val & 0xe3 } else { val }
+            },
+            7 => {
+                let v = self.v & 0x3fff;
+                if v >= 0x3f00 {
+                    self.palette_read(v) | self.latch & 0xc0
+                } else {
+                    self.read_buffer
+                }
+            },
+            _ => self.latch,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, val: u8, cartridge: &mut Cartridge) {
+        self.latch = val;
+        match addr & 7 {
+            0 => {
+                let nmi_enabled = self.ctrl & CTRL_NMI != 0;
+                self.ctrl = val;
+                self.t = self.t & !0x0c00 | (val as u16 & 0x03) << 10;
+
+                /* Enabling NMI during vblank triggers one straight away */
+                if !nmi_enabled && val & CTRL_NMI != 0 && self.status & STATUS_VBLANK != 0 {
+                    self.nmi_pending = true;
+                }
+            },
+            1 => self.mask = val,
+            3 => self.oam_addr = val,
+            4 => {
+                self.oam[self.oam_addr as usize] = val;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            },
+            5 => if !self.w {
+                self.t = self.t & !0x001f | (val as u16) >> 3;
+                self.x = val & 0x07;
+                self.w = true;
+            } else {
+                self.t = self.t & !0x73e0 | (val as u16 & 0x07) << 12 | (val as u16 & 0xf8) << 2;
+                self.w = false;
+            },
+            6 => if !self.w {
+                self.t = self.t & 0x00ff | (val as u16 & 0x3f) << 8;
+                self.w = true;
+            } else {
+                self.t = self.t & 0xff00 | val as u16;
+                self.v = self.t;
+                self.w = false;
+            },
+            7 => {
+                let v = self.v;
+                self.vram_write(v, val, cartridge);
+                self.increment_v();
+            },
+            _ => {},
+        }
+    }
+
+    fn increment_v(&mut self) {
+        let step = if self.ctrl & CTRL_INCREMENT != 0 { 32 } else { 1 };
+        self.v = self.v.wrapping_add(step) & 0x7fff;
+    }
+
+    /* PPU bus */
+    fn vram_read(&self, addr: u16, cartridge: &Cartridge) -> u8 {
+        let addr = addr & 0x3fff;
+        match addr {
+            0x0000 ..= 0x1fff => cartridge.chr_read8(addr),
+            0x2000 ..= 0x3eff => cartridge.nametable_read8(&self.vram, 0x2000 | (addr & 0x0fff)),
+            _ => self.palette_read(addr),
+        }
+    }
+
+    fn vram_write(&mut self, addr: u16, val: u8, cartridge: &mut Cartridge) {
+        let addr = addr & 0x3fff;
+        match addr {
+            0x0000 ..= 0x1fff => cartridge.chr_write8(addr, val),
+            0x2000 ..= 0x3eff => cartridge.nametable_write8(&mut self.vram, 0x2000 | (addr & 0x0fff), val),
+            _ => self.palette[palette_index(addr)] = val & 0x3f,
+        }
+    }
+
+    fn palette_read(&self, addr: u16) -> u8 {
+        let val = self.palette[palette_index(addr)];
+        if self.mask & MASK_GRAYSCALE != 0 { val & 0x30 } else { val }
+    }
+
+    /* Runs one dot */
+    pub fn step(&mut self, cartridge: &Cartridge) {
+        match self.scanline {
+            0 ..= 239 => self.render_dot(cartridge),
+            line if line == self.vblank_line && self.dot == 1 => {
+                self.status |= STATUS_VBLANK;
+                self.frame += 1;
+                if self.ctrl & CTRL_NMI != 0 {
+                    self.nmi_pending = true;
+                }
+            },
+            line if line == self.pre_render_line => {
+                if self.dot == 1 {
+                    self.status &= !(STATUS_VBLANK | STATUS_SPRITE0 | STATUS_OVERFLOW);
+                }
+                self.render_dot(cartridge);
+
+                /* Odd frames skip the last dot of the pre-render line */
+                if self.dot == 339 && self.odd_frame && self.rendering() && self.region.skips_odd_dot() {
+                    self.dot = 340;
+                    self.render_dot(cartridge);
+                }
+            },
+            _ => {},
+        }
+
+        self.dot += 1;
+        if self.dot == DOTS {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline > self.pre_render_line {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    fn render_dot(&mut self, cartridge: &Cartridge) {
+        if !self.rendering() {
+            if self.scanline < 240 && self.dot >= 1 && self.dot <= 256 {
+                let color = self.palette_read(0x3f00);
+                self.put_pixel(self.dot as usize - 1, color);
+            }
+            return;
+        }
+
+        let pre_render = self.scanline == self.pre_render_line;
+        match self.dot {
+            1 => self.bg_addr = self.nametable_addr(),
+            2 ..= 255 | 322 ..= 337 => {
+                self.pixel();
+                match self.dot % 8 {
+                    1 => {
+                        self.bg_addr = self.nametable_addr();
+                        self.reload_shifters();
+                    },
+                    2 => self.nametable = self.vram_read(self.bg_addr, cartridge),
+                    3 => self.bg_addr = self.attribute_addr(),
+                    4 => {
+                        let v = self.v;
+                        let shift = (v >> 4) & 0x04 | v & 0x02;
+                        self.attribute = (self.vram_read(self.bg_addr, cartridge) >> shift) & 0x03;
+                    },
+                    5 => self.bg_addr = self.pattern_addr(),
+                    6 => self.bg_low = self.vram_read(self.bg_addr, cartridge),
+                    7 => self.bg_addr += 8,
+                    _ => {
+                        self.bg_high = self.vram_read(self.bg_addr, cartridge);
+                        self.increment_x();
+                    },
+                }
+            },
+            256 => {
+                self.pixel();
+                self.bg_high = self.vram_read(self.bg_addr, cartridge);
+                self.increment_y();
+            },
+            257 => {
+                self.pixel();
+                self.reload_shifters();
+                self.copy_x();
+                self.evaluate_sprites(pre_render);
+            },
+            258 ..= 320 => {
+                let slot = (self.dot as usize - 257) / 8;
+                match (self.dot - 257) % 8 {
+                    /* Garbage nametable fetches */
+                    1 | 3 => {
+                        let addr = self.nametable_addr();
+                        self.vram_read(addr, cartridge);
+                    },
+                    5 => {
+                        let addr = self.sprite_addr(slot);
+                        self.sprites[slot].low = self.vram_read(addr, cartridge);
+                    },
+                    7 => {
+                        let addr = self.sprite_addr(slot) + 8;
+                        self.sprites[slot].high = self.vram_read(addr, cartridge);
+                        if slot >= self.sprite_count {
+                            self.sprites[slot].low = 0;
+                            self.sprites[slot].high = 0;
+                        }
+                    },
+                    _ => {},
+                }
+
+                if pre_render && self.dot >= 280 && self.dot <= 304 {
+                    self.copy_y();
+                }
+            },
+            321 | 339 => self.bg_addr = self.nametable_addr(),
+            338 | 340 => self.nametable = self.vram_read(self.bg_addr, cartridge),
+            _ => {},
+        }
+    }
+
+    /* Outputs the pixel for dot - 2, then shifts the background registers */
+    fn pixel(&mut self) {
+        let x = self.dot as usize - 2;
+        if self.scanline < 240 && x < SCREEN_WIDTH {
+            let color = self.compose_pixel(x);
+            self.put_pixel(x, color);
+        }
+
+        self.bg_shift_low <<= 1;
+        self.bg_shift_high <<= 1;
+        self.attr_shift_low <<= 1;
+        self.attr_shift_high <<= 1;
+    }
+
+    fn compose_pixel(&mut self, x: usize) -> u8 {
+        let mut palette = 0;
+
+        if self.mask & MASK_BG != 0 && (x >= 8 || self.mask & MASK_BG_LEFT != 0) {
+            let bit = 15 - self.x;
+            let pattern =
+                ((self.bg_shift_high >> bit) & 1) << 1 |
+                ((self.bg_shift_low >> bit) & 1);
+            if pattern != 0 {
+                let attribute =
+                    ((self.attr_shift_high >> bit) & 1) << 1 |
+                    ((self.attr_shift_low >> bit) & 1);
+                palette = (attribute << 2 | pattern) as u8;
+            }
+        }
+
+        if self.mask & MASK_SPRITES != 0 && (x >= 8 || self.mask & MASK_SPRITE_LEFT != 0) {
+            for slot in 0..self.sprite_count {
+                let sprite = self.sprites[slot];
+                let offset = x.wrapping_sub(sprite.x as usize);
+                if offset >= 8 {
+                    continue;
+                }
+
+                let bit = if sprite.attr & 0x40 != 0 { offset } else { 7 - offset };
+                let pattern = ((sprite.high >> bit) & 1) << 1 | ((sprite.low >> bit) & 1);
+                if pattern == 0 {
+                    continue;
+                }
+
+                if slot == 0 && self.sprite0_in_line && palette != 0 && x != 255 {
+                    self.status |= STATUS_SPRITE0;
+                }
+
+                /* The first opaque sprite wins, even if it's behind the background */
+                if palette == 0 || sprite.attr & 0x20 == 0 {
+                    palette = 0x10 | (sprite.attr & 0x03) << 2 | pattern;
+                }
+                break;
+            }
+        }
+
+        self.palette_read(0x3f00 | palette as u16)
+    }
+
+    fn put_pixel(&mut self, x: usize, color: u8) {
+        let mut emphasis = (self.mask as u16 & 0xe0) << 1;
+        if self.region.swaps_emphasis() {
+            emphasis = emphasis & 0x100 | (emphasis & 0x40) << 1 | (emphasis & 0x80) >> 1;
+        }
+        self.framebuffer[self.scanline as usize * SCREEN_WIDTH + x] = emphasis | color as u16;
+    }
+
+    fn reload_shifters(&mut self) {
+        self.bg_shift_low = self.bg_shift_low & 0xff00 | self.bg_low as u16;
+        self.bg_shift_high = self.bg_shift_high & 0xff00 | self.bg_high as u16;
+
+        let low = if self.attribute & 1 != 0 { 0xff } else { 0x00 };
+        let high = if self.attribute & 2 != 0 { 0xff } else { 0x00 };
+        self.attr_shift_low = self.attr_shift_low & 0xff00 | low;
+        self.attr_shift_high = self.attr_shift_high & 0xff00 | high;
+    }
+
+    /* Scrolling */
+    fn nametable_addr(&self) -> u16 {
+        0x2000 | (self.v & 0x0fff)
+    }
+
+    fn attribute_addr(&self) -> u16 {
+        let v = self.v;
+        0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07)
+    }
+
+    fn pattern_addr(&self) -> u16 {
+        let table = if self.ctrl & CTRL_BG_TABLE != 0 { 0x1000 } else { 0 };
+        table | (self.nametable as u16) << 4 | (self.v >> 12) & 0x07
+    }
+
+    fn increment_x(&mut self) {
+        let v = self.v;
+        if v & 0x001f == 31 {
+            self.v = (v & !0x001f) ^ 0x0400;
+        } else {
+            self.v = v + 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        let v = self.v;
+        if v & 0x7000 != 0x7000 {
+            self.v = v + 0x1000;
+            return;
+        }
+
+        let mut v = v & !0x7000;
+        let mut y = (v & 0x03e0) >> 5;
+        if y == 29 {
+            y = 0;
+            v ^= 0x0800;
+        } else if y == 31 {
+            y = 0;
+        } else {
+            y += 1;
+        }
+        self.v = v & !0x03e0 | y << 5;
+    }
+
+    fn copy_x(&mut self) {
+        self.v = self.v & !0x041f | self.t & 0x041f;
+    }
+
+    fn copy_y(&mut self) {
+        self.v = self.v & !0x7be0 | self.t & 0x7be0;
+    }
+
+    /* Sprites */
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_SPRITE_SIZE != 0 { 16 } else { 8 }
+    }
+
+    /* Finds the sprites on the next line, there's no evaluation on the pre-render line */
+    fn evaluate_sprites(&mut self, pre_render: bool) {
+        self.secondary_oam = [0xff; 32];
+        self.sprite_count = 0;
+        self.sprite0_in_line = false;
+        if pre_render {
+            return;
+        }
+
+        let height = self.sprite_height();
+        for sprite in 0..64 {
+            let y = self.oam[sprite * 4] as u16;
+            if self.scanline.wrapping_sub(y) >= height {
+                continue;
+            }
+
+            if self.sprite_count == 8 {
+                self.status |= STATUS_OVERFLOW;
+                break;
+            }
+
+            let slot = self.sprite_count * 4;
+            self.secondary_oam[slot..slot + 4].copy_from_slice(&self.oam[sprite * 4..sprite * 4 + 4]);
+            if sprite == 0 {
+                self.sprite0_in_line = true;
+            }
+            self.sprite_count += 1;
+        }
+    }
+
+    fn sprite_addr(&mut self, slot: usize) -> u16 {
+        let y = self.secondary_oam[slot * 4] as u16;
+        let tile = self.secondary_oam[slot * 4 + 1] as u16;
+        let attr = self.secondary_oam[slot * 4 + 2];
+        self.sprites[slot].attr = attr;
+        self.sprites[slot].x = self.secondary_oam[slot * 4 + 3];
+
+        let height = self.sprite_height();
+        let mut row = self.scanline.wrapping_sub(y) % height;
+        if attr & 0x80 != 0 {
+            row = height - 1 - row;
+        }
+
+        if height == 16 {
+            let table = (tile & 1) << 12;
+            let tile = (tile & 0xfe) + (row >> 3);
+            table | tile << 4 | (row & 0x07)
+        } else {
+            let table = if self.ctrl & CTRL_SPRITE_TABLE != 0 { 0x1000 } else { 0 };
+            table | tile << 4 | row
+        }
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Ppu {
+        Ppu::new()
+    }
+}
+
+/* $3F10/$3F14/$3F18/$3F1C mirror the background entries */
+fn palette_index(addr: u16) -> usize {
+    let index = addr as usize & 0x1f;
+    if index & 0x13 == 0x10 { index & !0x10 } else { index }
+}
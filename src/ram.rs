@@ -0,0 +1,56 @@
+/*
+ * What RAM holds at power on. Real consoles come up with semi-random
+ * contents, so games that read memory before writing it can behave
+ * differently from one console, or emulator, to the next.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum RamInit {
+    #[default]
+    Zeros,
+    Ones,
+    /* 4 bytes of $00 then 4 bytes of $FF, like FCEUX */
+    Alternating,
+    /* The same seed always gives the same contents */
+    Random(u64),
+}
+
+/* Each memory gets its own stream, so they don't all start out the same */
+pub const STREAM_RAM: u64 = 0;
+pub const STREAM_WRAM: u64 = 1;
+pub const STREAM_OAM: u64 = 2;
+
+impl RamInit {
+    pub fn fill(self, data: &mut [u8], stream: u64) {
+        match self {
+            RamInit::Zeros => data.iter_mut().for_each(|byte| *byte = 0x00),
+            RamInit::Ones => data.iter_mut().for_each(|byte| *byte = 0xff),
+            RamInit::Alternating => for (i, byte) in data.iter_mut().enumerate() {
+                *byte = if i & 4 != 0 { 0xff } else { 0x00 };
+            },
+            RamInit::Random(seed) => {
+                /* xorshift64*, which can't start at 0 */
+                let mut state = (seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1;
+                for byte in data.iter_mut() {
+                    state ^= state >> 12;
+                    state ^= state << 25;
+                    state ^= state >> 27;
+                    *byte = (state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8;
+                }
+            },
+        }
+    }
+
+    /* Parses "zeros", "ones", "alternating", "random" (seed 0) or "random:SEED" */
+    pub fn from_name(name: &str) -> Option<RamInit> {
+        match name {
+            "zeros" => Some(RamInit::Zeros),
+            "ones" => Some(RamInit::Ones),
+            "alternating" => Some(RamInit::Alternating),
+            "random" => Some(RamInit::Random(0)),
+            _ => {
+                let seed = name.strip_prefix("random:")?;
+                seed.parse().ok().map(RamInit::Random)
+            },
+        }
+    }
+}
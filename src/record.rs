@@ -0,0 +1,359 @@
+use nes::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use region::Region;
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/*
+ * Writes gameplay to disk, either as a Y4M video with a WAV file next to it
+ * or as a single uncompressed AVI. Audio is 16 bit mono PCM.
+ *
+ * References:
+ *  - https://wiki.multimedia.cx/index.php/YUV4MPEG2
+ *  - https://docs.microsoft.com/en-us/windows/win32/directshow/avi-riff-file-reference
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RecordFormat {
+    /* Writes `name.y4m` and `name.wav` */
+    Y4m,
+    Avi,
+}
+
+impl RecordFormat {
+    /* Guesses the format from a file extension, defaulting to AVI */
+    pub fn from_path<P: AsRef<Path>>(path: P) -> RecordFormat {
+        let extension = path.as_ref().extension().and_then(|extension| extension.to_str());
+        match extension {
+            Some(extension) if extension.eq_ignore_ascii_case("y4m") => RecordFormat::Y4m,
+            _ => RecordFormat::Avi,
+        }
+    }
+}
+
+const FRAME_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
+
+pub struct Recorder {
+    output: Output,
+    /* Only every Nth frame is kept, the audio is always complete */
+    every: usize,
+    frame: usize,
+}
+
+enum Output {
+    Y4m {
+        video: BufWriter<File>,
+        audio: WavWriter,
+    },
+    Avi(AviWriter),
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P, format: RecordFormat, region: Region,
+                                  sample_rate: u32, every: usize) -> io::Result<Recorder> {
+        let every = every.max(1);
+        let (numerator, denominator) = region.frame_rate();
+        let denominator = denominator * every as u32;
+
+        let path = path.as_ref();
+        let output = match format {
+            RecordFormat::Y4m => {
+                let mut video = BufWriter::new(File::create(path.with_extension("y4m"))?);
+                writeln!(video, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444",
+                         SCREEN_WIDTH, SCREEN_HEIGHT, numerator, denominator)?;
+                let audio = WavWriter::create(&path.with_extension("wav"), sample_rate)?;
+                Output::Y4m { video, audio }
+            },
+            RecordFormat::Avi => {
+                Output::Avi(AviWriter::create(path, numerator, denominator, sample_rate)?)
+            },
+        };
+
+        Ok(Recorder {
+            output,
+            every,
+            frame: 0,
+        })
+    }
+
+    /* `rgb` is one frame with 3 bytes per pixel, `audio` the samples that go with it */
+    pub fn add_frame(&mut self, rgb: &[u8], audio: &[f32]) -> io::Result<()> {
+        let keep = self.frame.is_multiple_of(self.every);
+        self.frame += 1;
+
+        match self.output {
+            Output::Y4m { ref mut video, audio: ref mut audio_out } => {
+                if keep {
+                    video.write_all(b"FRAME\n")?;
+                    video.write_all(&rgb_to_yuv444(rgb))?;
+                }
+                audio_out.write_samples(audio)
+            },
+            Output::Avi(ref mut avi) => {
+                if keep {
+                    avi.write_video(rgb)?;
+                }
+                avi.write_audio(audio)
+            },
+        }
+    }
+
+    /* Fills in the sizes in the headers, the files are incomplete without this */
+    pub fn finish(self) -> io::Result<()> {
+        match self.output {
+            Output::Y4m { mut video, audio: audio_out } => {
+                video.flush()?;
+                audio_out.finish()
+            },
+            Output::Avi(avi) => avi.finish(),
+        }
+    }
+}
+
+/* Planar BT.601, studio range */
+fn rgb_to_yuv444(rgb: &[u8]) -> Vec<u8> {
+    let pixels = rgb.len() / 3;
+    let mut yuv = vec![0; pixels * 3];
+    for (i, pixel) in rgb.chunks(3).enumerate() {
+        let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+        yuv[i] = (16.0 + (65.738 * r + 129.057 * g + 25.064 * b) / 256.0) as u8;
+        yuv[pixels + i] = (128.0 + (-37.945 * r - 74.494 * g + 112.439 * b) / 256.0) as u8;
+        yuv[pixels * 2 + i] = (128.0 + (112.439 * r - 94.154 * g - 18.285 * b) / 256.0) as u8;
+    }
+    yuv
+}
+
+fn pcm16(samples: &[f32]) -> Vec<u8> {
+    let mut pcm = Vec::with_capacity(samples.len() * 2);
+    for &sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * 32767.0) as i16;
+        pcm.extend_from_slice(&sample.to_le_bytes());
+    }
+    pcm
+}
+
+/* WAVEFORMATEX for 16 bit mono PCM, shared by WAV and AVI */
+fn wave_format(sample_rate: u32) -> Vec<u8> {
+    let mut format = Vec::new();
+    format.extend_from_slice(&1u16.to_le_bytes());
+    format.extend_from_slice(&1u16.to_le_bytes());
+    format.extend_from_slice(&sample_rate.to_le_bytes());
+    format.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    format.extend_from_slice(&2u16.to_le_bytes());
+    format.extend_from_slice(&16u16.to_le_bytes());
+    format
+}
+
+/* RIFF sizes are 32 bit, so anything bigger can't be written */
+fn to_u32(val: u64) -> io::Result<u32> {
+    u32::try_from(val).map_err(|_| io::Error::other("file is too large"))
+}
+
+fn write_u32_at(file: &mut BufWriter<File>, offset: u64, val: u32) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&val.to_le_bytes())
+}
+
+struct WavWriter {
+    file: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, sample_rate: u32) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let format = wave_format(sample_rate);
+        file.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        file.write_all(&(format.len() as u32).to_le_bytes())?;
+        file.write_all(&format)?;
+        file.write_all(b"data\0\0\0\0")?;
+
+        Ok(WavWriter {
+            file,
+            data_size: 0,
+        })
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let pcm = pcm16(samples);
+        /* Leave room for the rest of the RIFF chunk */
+        let data_size = self.data_size as u64 + pcm.len() as u64;
+        to_u32(data_size + 36)?;
+        self.data_size = data_size as u32;
+        self.file.write_all(&pcm)
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        let data_offset = 12 + 8 + 16 + 4;
+        write_u32_at(&mut self.file, 4, data_offset as u32 - 4 + self.data_size)?;
+        write_u32_at(&mut self.file, data_offset, self.data_size)?;
+        self.file.flush()
+    }
+}
+
+/*
+ * AVI 1.0 with an idx1 index, so files are limited to 1GB, which is about
+ * 90 minutes of NTSC video. Chunks that would go past that are refused, and
+ * what's already written can still be finished. Frames are stored as
+ * bottom-up 24 bit BGR.
+ */
+const MAX_AVI_SIZE: u64 = 1 << 30;
+
+struct AviWriter {
+    file: BufWriter<File>,
+    /* Offsets of header fields that are only known at the end */
+    total_frames_offset: u64,
+    video_length_offset: u64,
+    audio_length_offset: u64,
+    movi_offset: u64,
+    position: u64,
+    index: Vec<(&'static [u8; 4], u32, u32)>,
+    frames: u32,
+    samples: u32,
+}
+
+impl AviWriter {
+    fn create(path: &Path, numerator: u32, denominator: u32, sample_rate: u32) -> io::Result<AviWriter> {
+        let mut header = Vec::new();
+
+        let list = |header: &mut Vec<u8>, kind: &[u8; 4]| -> usize {
+            header.extend_from_slice(b"LIST\0\0\0\0");
+            header.extend_from_slice(kind);
+            header.len() - 4
+        };
+        let end_list = |header: &mut Vec<u8>, start: usize| {
+            let size = (header.len() - start) as u32;
+            header[start - 4..start].copy_from_slice(&size.to_le_bytes());
+        };
+        let chunk = |header: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]| {
+            header.extend_from_slice(kind);
+            header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            header.extend_from_slice(data);
+        };
+        let u32s = |values: &[u32]| -> Vec<u8> {
+            values.iter().flat_map(|value| value.to_le_bytes().to_vec()).collect()
+        };
+
+        header.extend_from_slice(b"RIFF\0\0\0\0AVI ");
+        let hdrl = list(&mut header, b"hdrl");
+
+        let micros_per_frame = (1_000_000u64 * denominator as u64 / numerator as u64) as u32;
+        let total_frames_offset = header.len() as u64 + 8 + 16;
+        chunk(&mut header, b"avih", &u32s(&[
+            micros_per_frame, 0, 0, 0x10 /* AVIF_HASINDEX */, 0, 0, 2, 0,
+            SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, 0, 0, 0, 0,
+        ]));
+
+        let strl = list(&mut header, b"strl");
+        let video_length_offset = header.len() as u64 + 8 + 32;
+        let mut strh = b"vidsDIB ".to_vec();
+        strh.extend_from_slice(&u32s(&[0, 0, 0, denominator, numerator, 0, 0, FRAME_SIZE as u32, 0xffff_ffff, 0, 0, 0]));
+        chunk(&mut header, b"strh", &strh);
+        let mut bitmap = u32s(&[40, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32]);
+        bitmap.extend_from_slice(&1u16.to_le_bytes());
+        bitmap.extend_from_slice(&24u16.to_le_bytes());
+        bitmap.extend_from_slice(&u32s(&[0, FRAME_SIZE as u32, 0, 0, 0, 0]));
+        chunk(&mut header, b"strf", &bitmap);
+        end_list(&mut header, strl);
+
+        let strl = list(&mut header, b"strl");
+        let audio_length_offset = header.len() as u64 + 8 + 32;
+        let mut strh = b"auds\0\0\0\0".to_vec();
+        strh.extend_from_slice(&u32s(&[0, 0, 0, 1, sample_rate, 0, 0, sample_rate * 2, 0xffff_ffff, 2, 0, 0]));
+        chunk(&mut header, b"strh", &strh);
+        chunk(&mut header, b"strf", &wave_format(sample_rate));
+        end_list(&mut header, strl);
+
+        end_list(&mut header, hdrl);
+
+        header.extend_from_slice(b"LIST\0\0\0\0movi");
+        let movi_offset = header.len() as u64 - 4;
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&header)?;
+
+        Ok(AviWriter {
+            file,
+            total_frames_offset,
+            video_length_offset,
+            audio_length_offset,
+            movi_offset,
+            position: header.len() as u64,
+            index: Vec::new(),
+            frames: 0,
+            samples: 0,
+        })
+    }
+
+    fn write_chunk(&mut self, kind: &'static [u8; 4], data: &[u8]) -> io::Result<()> {
+        /* Including the chunk's padding and the index entries finish() adds */
+        let size = data.len() as u64;
+        let end = self.position + 8 + size + size % 2 + 8 + (self.index.len() as u64 + 1) * 16;
+        if end > MAX_AVI_SIZE {
+            return Err(io::Error::other("AVI files are limited to 1GB"));
+        }
+
+        let offset = to_u32(self.position - self.movi_offset)?;
+        let size = to_u32(size)?;
+        self.index.push((kind, offset, size));
+
+        self.file.write_all(kind)?;
+        self.file.write_all(&size.to_le_bytes())?;
+        self.file.write_all(data)?;
+        self.position += 8 + data.len() as u64;
+        if data.len() % 2 == 1 {
+            self.file.write_all(&[0])?;
+            self.position += 1;
+        }
+        Ok(())
+    }
+
+    fn write_video(&mut self, rgb: &[u8]) -> io::Result<()> {
+        let row = SCREEN_WIDTH * 3;
+        let mut bgr = Vec::with_capacity(FRAME_SIZE);
+        for line in rgb[..FRAME_SIZE].chunks(row).rev() {
+            for pixel in line.chunks(3) {
+                bgr.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+        }
+
+        self.write_chunk(b"00db", &bgr)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn write_audio(&mut self, samples: &[f32]) -> io::Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        self.write_chunk(b"01wb", &pcm16(samples))?;
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        let movi_size = to_u32(self.position - self.movi_offset)?;
+        let index_size = to_u32(self.index.len() as u64 * 16)?;
+
+        self.file.write_all(b"idx1")?;
+        self.file.write_all(&index_size.to_le_bytes())?;
+        for &(kind, offset, size) in &self.index {
+            /* AVIIF_KEYFRAME, every frame is uncompressed */
+            self.file.write_all(kind)?;
+            self.file.write_all(&0x10u32.to_le_bytes())?;
+            self.file.write_all(&offset.to_le_bytes())?;
+            self.file.write_all(&size.to_le_bytes())?;
+        }
+        let end = self.position + 8 + index_size as u64;
+
+        write_u32_at(&mut self.file, 4, to_u32(end - 8)?)?;
+        write_u32_at(&mut self.file, self.movi_offset - 4, movi_size)?;
+        write_u32_at(&mut self.file, self.total_frames_offset, self.frames)?;
+        write_u32_at(&mut self.file, self.video_length_offset, self.frames)?;
+        write_u32_at(&mut self.file, self.audio_length_offset, self.samples)?;
+        self.file.flush()
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * References:
+ *  - http://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+ *  - http://wiki.nesdev.com/w/index.php/Clock_rate
+ *
+ * Dendy is a PAL famiclone: PAL's master clock and line count, but NTSC's
+ * 3 dots per CPU cycle, NTSC APU timing, and vblank starting 50 lines late.
+ */
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    /* Master clock cycles per CPU cycle */
+    pub fn cpu_divider(self) -> u64 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    /* Master clock cycles per PPU dot */
+    pub fn ppu_divider(self) -> u64 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    /* Hz */
+    pub fn master_clock(self) -> f64 {
+        match self {
+            Region::Ntsc => 236_250_000.0 / 11.0,
+            Region::Pal | Region::Dendy => 26_601_712.5,
+        }
+    }
+
+    pub fn cpu_frequency(self) -> f64 {
+        self.master_clock() / self.cpu_divider() as f64
+    }
+
+    /* Scanlines per frame, including the pre-render line */
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /* The scanline vblank and NMI start on */
+    pub fn vblank_line(self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /* Only the NTSC PPU skips a dot on odd frames */
+    pub fn skips_odd_dot(self) -> bool {
+        self == Region::Ntsc
+    }
+
+    /* The 2C07 has the red and green emphasis bits the other way around */
+    pub fn swaps_emphasis(self) -> bool {
+        self == Region::Pal
+    }
+
+    /* Dendy keeps the NTSC APU */
+    pub fn pal_apu(self) -> bool {
+        self == Region::Pal
+    }
+
+    /*
+     * Frames per second as a fraction. NTSC frames alternate between
+     * 89342 and 89341 dots, the others are always 106392.
+     */
+    pub fn frame_rate(self) -> (u32, u32) {
+        match self {
+            Region::Ntsc => (39_375_000, 655_171),
+            Region::Pal | Region::Dendy => (322_445, 6_448),
+        }
+    }
+
+    /*
+     * NES 2.0 byte 12. Multi-region ROMs give None, so the game database
+     * gets a say before falling back to NTSC.
+     */
+    pub fn from_nes2(timing: u8) -> Option<Region> {
+        match timing & 0x03 {
+            0 => Some(Region::Ntsc),
+            1 => Some(Region::Pal),
+            3 => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,32 @@
+extern crate rusty_nest;
+
+use rusty_nest::klaus::{self, Test};
+
+use std::path::Path;
+
+/*
+ * Runs Klaus Dormann's functional and decimal tests, both on the Nmos6502
+ * variant. The binaries aren't distributed with the repo, so these only
+ * run with --ignored, once they're copied to rom/.
+ *
+ * References:
+ *  - https://github.com/Klaus2m5/6502_65C02_functional_tests
+ */
+fn run(name: &str, test: &Test) {
+    let binary = Path::new(env!("CARGO_MANIFEST_DIR")).join("rom").join(name);
+    let result = klaus::run_file(&binary, test, klaus::MAX_CYCLES)
+        .unwrap_or_else(|err| panic!("Failed to run {}: {:?}", binary.display(), err));
+    assert!(result.passed, "trapped at ${:04X}, error {:?}", result.trap, result.error);
+}
+
+#[test]
+#[ignore = "needs rom/6502_functional_test.bin, the default build"]
+fn functional() {
+    run("6502_functional_test.bin", &klaus::FUNCTIONAL);
+}
+
+#[test]
+#[ignore = "needs rom/6502_decimal_test.bin, built to end with JMP *"]
+fn decimal() {
+    run("6502_decimal_test.bin", &klaus::DECIMAL);
+}
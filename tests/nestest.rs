@@ -0,0 +1,36 @@
+extern crate rusty_nest;
+
+use rusty_nest::{Cartridge, Cpu, Mem, MemMap};
+
+use std::fs::{self, File};
+use std::path::Path;
+
+/*
+ * Runs nestest in its automated mode, from $C000, against the Nintendulator
+ * log in test/. The ROM isn't distributed with the repo, so this only runs
+ * with --ignored, once it's copied to rom/nestest.nes.
+ *
+ * References:
+ *  - http://www.qmtpro.com/~nes/misc/nestest.txt
+ */
+#[test]
+#[ignore = "needs rom/nestest.nes"]
+fn nestest() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let rom = root.join("rom/nestest.nes");
+    let mut file = File::open(&rom).unwrap_or_else(|err| panic!("Failed to open {}: {}", rom.display(), err));
+    let cartridge = Cartridge::new(&mut file).unwrap();
+    let mut cpu = Cpu::new(MemMap::new(cartridge));
+
+    let log = fs::read_to_string(root.join("test/nestest.log")).unwrap();
+    let first = log.lines().next().unwrap();
+    assert!(cpu.align_to_log(first).is_some(), "nestest.log has no PPU positions");
+    if let Some(mismatch) = cpu.compare_log(log.as_bytes(), false).unwrap() {
+        panic!("Trace differs from nestest.log at line {}\n- {}\n+ {}",
+               mismatch.line, mismatch.expected, mismatch.obtained);
+    }
+
+    /* Error codes for the official and unofficial opcode tests, 0 if they all passed */
+    assert_eq!(cpu.mem().peek8(0x0002), 0x00, "official opcode tests failed");
+    assert_eq!(cpu.mem().peek8(0x0003), 0x00, "unofficial opcode tests failed");
+}